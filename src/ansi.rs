@@ -0,0 +1,21 @@
+// Just enough ANSI escape codes to color error messages, which isn't worth a
+// dependency
+
+use std::io::IsTerminal;
+
+pub const RED: &str = "\x1b[31m";
+pub const CYAN: &str = "\x1b[36m";
+pub const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+pub fn paint(color: &str, text: &str) -> String {
+    format!("{}{}{}", color, text, RESET)
+}
+
+// Without a flag either way, color errors only if they're going to a
+// terminal and the user hasn't asked for no color (https://no-color.org)
+pub fn use_color(forced: Option<bool>) -> bool {
+    forced.unwrap_or_else(|| {
+        std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+    })
+}
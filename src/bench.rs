@@ -0,0 +1,170 @@
+// Compares the two instruction pointers by decoding a script's bytecode over
+// and over with each of them. TracingIP keeps the current line up to date as
+// it reads, and this measures what that costs on every instruction, which is
+// the reason IP exists at all. It also times a tight while loop run by the
+// VM, where the cost is almost all instruction dispatch.
+
+use crate::value::{Function, ObjectRoot, Value};
+use crate::{compiler, Chunk, OpCode, TracingIP, IP, VM};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+const ROUNDS: usize = 2000;
+
+const LOOP_ITERATIONS: usize = 1_000_000;
+const TIGHT_LOOP: &str = "
+fun loop() {
+  var i = 0;
+  var total = 0;
+  while (i < 1000000) {
+    total = total + i;
+    i = i + 1;
+  }
+  return total;
+}
+loop();";
+
+// Reads every instruction and its operands the same way the VM would, and
+// returns the number of instructions
+macro_rules! walk_chunk {
+    ($ip:ident, $chunk:expr) => {{
+        let mut ip = $ip::new($chunk, 0);
+        let mut count = 0;
+        while ip.valid() {
+            match OpCode::try_from(ip.read()) {
+                Ok(OpCode::Constant)
+                | Ok(OpCode::Call)
+                | Ok(OpCode::TailCall)
+                | Ok(OpCode::PopN)
+                | Ok(OpCode::BuildTuple)
+                | Ok(OpCode::Unpack)
+                | Ok(OpCode::GetLocal)
+                | Ok(OpCode::SetLocal)
+                | Ok(OpCode::GetUpvalue)
+                | Ok(OpCode::SetUpvalue) => {
+                    ip.read();
+                }
+                Ok(OpCode::GetGlobal) | Ok(OpCode::DefineGlobal) | Ok(OpCode::SetGlobal) => {
+                    ip.read();
+                    ip.read_short();
+                }
+                Ok(OpCode::GetGlobalLong)
+                | Ok(OpCode::DefineGlobalLong)
+                | Ok(OpCode::SetGlobalLong) => {
+                    ip.read_short();
+                    ip.read_short();
+                }
+                Ok(OpCode::Jump)
+                | Ok(OpCode::JumpIfFalse)
+                | Ok(OpCode::PopJumpIfFalse)
+                | Ok(OpCode::PopJumpIfTrue)
+                | Ok(OpCode::JumpIfNotNil)
+                | Ok(OpCode::Loop)
+                | Ok(OpCode::GetLocalLong)
+                | Ok(OpCode::SetLocalLong) => {
+                    ip.read_short();
+                }
+                Ok(OpCode::ConstantLong) => {
+                    ip.read_constant_long();
+                }
+                Ok(op @ (OpCode::Closure | OpCode::ClosureLong)) => {
+                    let function = match op {
+                        OpCode::Closure => ip.read_constant(),
+                        _ => ip.read_constant_long(),
+                    };
+                    if let Value::FunctionProto(f) = function {
+                        for _ in 0..f.upgrade().unwrap().content.upvalue_count {
+                            ip.read();
+                            ip.read_short();
+                        }
+                    }
+                }
+                _ => (),
+            }
+            count += 1;
+        }
+        count
+    }};
+}
+
+fn walk_fast(chunk: &Chunk) -> usize {
+    walk_chunk!(IP, chunk)
+}
+
+fn walk_tracing(chunk: &Chunk) -> usize {
+    walk_chunk!(TracingIP, chunk)
+}
+
+fn nested_functions(chunk: &Chunk, found: &mut Vec<ObjectRoot<Function>>) {
+    for constant in &chunk.constants {
+        if let Value::FunctionProto(oref) = constant {
+            let function = oref.upgrade().unwrap();
+            nested_functions(&function.content.chunk, found);
+            found.push(function);
+        }
+    }
+}
+
+fn time_walks(chunks: &[&Chunk], walk: fn(&Chunk) -> usize) -> (usize, Duration) {
+    let start = Instant::now();
+    let mut count = 0;
+    for _ in 0..ROUNDS {
+        for chunk in chunks {
+            count += walk(chunk);
+        }
+    }
+    (count, start.elapsed())
+}
+
+// The loop runs in a VM of its own, so it can't disturb the one the script
+// was compiled in
+fn time_tight_loop() -> Duration {
+    let mut vm = VM::new();
+    let function = compiler::compile(TIGHT_LOOP, &mut vm).unwrap();
+    let start = Instant::now();
+    vm.interpret_function(function).unwrap();
+    start.elapsed()
+}
+
+fn nanos_per_instruction(count: usize, elapsed: Duration) -> f64 {
+    elapsed.as_nanos() as f64 / count.max(1) as f64
+}
+
+// Returns the exit code, which is nonzero if the fast IP turned out slower
+pub fn bench_ip(vm: &mut VM, source: &str) -> i32 {
+    let function = match compiler::compile(source, vm) {
+        Ok(function) => function,
+        Err(_) => return 65,
+    };
+    let mut nested = Vec::new();
+    nested_functions(&function.chunk, &mut nested);
+    let mut chunks = vec![&function.chunk];
+    chunks.extend(nested.iter().map(|f| &f.content.chunk));
+
+    // run each once first so neither gets the benefit of a warm cache
+    time_walks(&chunks, walk_fast);
+    time_walks(&chunks, walk_tracing);
+    let (count, fast) = time_walks(&chunks, walk_fast);
+    let (_, tracing) = time_walks(&chunks, walk_tracing);
+
+    let fast = nanos_per_instruction(count, fast);
+    let tracing = nanos_per_instruction(count, tracing);
+    println!("{} instructions decoded {} times", count / ROUNDS, ROUNDS);
+    println!("fast IP:    {:.2} ns/instruction", fast);
+    println!(
+        "tracing IP: {:.2} ns/instruction ({:+.2} ns for line tracking)",
+        tracing,
+        tracing - fast
+    );
+    let elapsed = time_tight_loop();
+    println!(
+        "tight while loop: {:.2} ns/iteration",
+        elapsed.as_nanos() as f64 / LOOP_ITERATIONS as f64
+    );
+    // with the trace feature on, IP is TracingIP so there is nothing to compare
+    if fast > tracing && !cfg!(feature = "trace") {
+        eprintln!("The fast IP was slower than the tracing IP.");
+        return 1;
+    }
+    0
+}
@@ -1,27 +1,86 @@
-use crate::parser::{get_rule, Precedence};
+use crate::ansi;
+use crate::parser::{compound_assignment_op, get_rule, Precedence};
 use crate::scanner::{Scanner, Token, TokenType};
-use crate::value::{create_string, manage, Function, FunctionType, Value};
+use crate::value::{create_string, format_function_name, manage, Function, FunctionType, Value};
 use crate::VM;
 use crate::{Chunk, CompileError, CompilerResult, LineNo, OpCode};
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
 
-#[cfg(feature = "lox_errors")]
-use crate::value::format_function_name;
-
-fn report_error(message: &str, token: &Token) {
-    eprint!("[line {}] Error", token.line);
+fn report_error(vm: &VM, message: &str, token: &Token, source: &str) {
+    if vm.pretty_errors {
+        report_pretty_error(vm, message, token, source);
+        return;
+    }
+    eprint!("[{}] Error", describe_location(vm, token));
     match token.ttype {
         TokenType::EOF => eprint!(" at end"),
         tt if TokenType::error_message(tt).is_some() => (),
         _ => eprint!(" at '{}'", token.content.unwrap()),
     }
-    eprintln!(": {}", message)
+    eprintln!(": {}", message);
+    // clox only prints the message, so the snippet would get in the way of
+    // comparing the output with it
+    if !vm.compat_clox {
+        let (line, padding, len) = snippet(token, source);
+        eprintln!("    | {}", line);
+        eprintln!("    | {}{}", padding, "^".repeat(len));
+    }
+}
+
+// Runtime errors still only know the line, and clox never shows the column,
+// so --compat-clox leaves it out here too
+fn describe_location(vm: &VM, token: &Token) -> String {
+    if vm.compat_clox {
+        vm.describe_line(token.line)
+    } else {
+        format!("{}:{}", vm.describe_line(token.line), token.column)
+    }
+}
+
+// Finds the line of source the token is on, the padding to put before a
+// marker under the token, and how many characters of the token to mark.
+// Tabs in the line are kept as tabs in the padding, so that the marker lines
+// up however wide the terminal shows them.
+fn snippet<'a>(token: &Token, source: &'a str) -> (&'a str, String, usize) {
+    // tokens are slices of the source, so we can find where they start
+    let (start, len) = match token.content {
+        None => (source.len(), 1),
+        Some(content) => (
+            content.as_ptr() as usize - source.as_ptr() as usize,
+            content.chars().take_while(|&c| c != '\n').count().max(1),
+        ),
+    };
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    let padding = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    (&source[line_start..line_end], padding, len)
+}
+
+// Shows the line of source the error is on with the token underlined
+fn report_pretty_error(vm: &VM, message: &str, token: &Token, source: &str) {
+    eprintln!("{} {}", ansi::paint(ansi::RED, "error:"), message);
+    let (line, padding, len) = snippet(token, source);
+    let location = format!("[{}]", describe_location(vm, token));
+    eprintln!("{} | {}", ansi::paint(ansi::CYAN, &location), line);
+    eprintln!(
+        "{} | {}{}",
+        " ".repeat(location.len()),
+        padding,
+        ansi::paint(ansi::YELLOW, &"^".repeat(len))
+    );
 }
 
 pub struct Local<'src> {
     name: &'src str,
     depth: Option<usize>,
     is_captured: bool,
+    mutable: bool,
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -44,6 +103,32 @@ pub struct Compiler<'src, 'vm> {
     first_error: Option<CompileError>,
     panic_mode: bool,
     pub cc: ChunkCompiler<'src>,
+    // where the left operand of the infix operator being compiled begins, and
+    // how big the constant pool was at that point
+    pub operand_start: usize,
+    pub operand_constants: usize,
+    // how many statements and expressions the parser is inside, for --dump-parse
+    parse_depth: usize,
+}
+
+pub struct LoopContext {
+    start: usize,
+    scope_depth: usize,
+    break_jumps: Vec<usize>,
+    // the slots of each for loop variable's copy in the body and the variable
+    // itself, when the body gets its own copies
+    loop_variables: Vec<(usize, usize)>,
+}
+
+// Where the code for the last `and` or `or` expression went, so that if it
+// turns out to be a whole expression statement we can stop it producing the
+// value that is about to be popped
+pub struct LogicalJump {
+    op: OpCode,
+    left_start: usize,
+    jump: usize,
+    right_start: usize,
+    end: usize,
 }
 
 pub struct ChunkCompiler<'src> {
@@ -52,29 +137,40 @@ pub struct ChunkCompiler<'src> {
     locals: Vec<Local<'src>>,
     scope_depth: usize,
     upvalues: Vec<CompilerUpvalue>,
+    loops: Vec<LoopContext>,
+    last_logical: Option<LogicalJump>,
+    // where the code for the last call ended, to spot a call being returned
+    last_call_end: Option<usize>,
+    // how many values the expression being compiled has on the stack above
+    // the locals, for a block expression inside it to leave room for
+    pub temporaries: usize,
     enclosing: Option<Box<ChunkCompiler<'src>>>,
 }
 
 impl<'src> ChunkCompiler<'src> {
     pub fn new(vm: &mut VM, function_type: FunctionType) -> Self {
         let function = Function::new_in_vm(vm, None, 0);
-        let mut locals = Vec::new();
-        locals.push(Local {
+        let locals = vec![Local {
             name: "",
             depth: Some(0),
             is_captured: false,
-        });
+            mutable: true,
+        }];
         Self {
             function,
             function_type,
             locals,
             scope_depth: 0,
             upvalues: Vec::new(),
+            loops: Vec::new(),
+            last_logical: None,
+            last_call_end: None,
+            temporaries: 0,
             enclosing: None,
         }
     }
 
-    pub fn resolve_local(&mut self, name: &str) -> Result<Option<u8>, CompileError> {
+    pub fn resolve_local(&mut self, name: &str) -> Result<Option<u16>, CompileError> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name == name {
                 if local.depth.is_none() {
@@ -83,7 +179,7 @@ impl<'src> ChunkCompiler<'src> {
                 return Ok(Some(i.try_into().unwrap()));
             }
         }
-        return Ok(None);
+        Ok(None)
     }
 
     pub fn resolve_upvalue(&mut self, name: &str) -> Result<Option<u8>, CompileError> {
@@ -93,7 +189,7 @@ impl<'src> ChunkCompiler<'src> {
                 None => match ecc.resolve_upvalue(name)? {
                     None => Ok(None),
                     Some(upvalue) => Ok(Some(
-                        self.add_upvalue(upvalue, UpvalueCaptureType::EnclosingUpvalue)?,
+                        self.add_upvalue(upvalue.into(), UpvalueCaptureType::EnclosingUpvalue)?,
                     )),
                 },
                 Some(local) => {
@@ -106,9 +202,15 @@ impl<'src> ChunkCompiler<'src> {
         }
     }
 
-    pub fn add_upvalue(&mut self, index: u8, kind: UpvalueCaptureType) -> Result<u8, CompileError> {
+    // The index is a local's slot in the enclosing function, or one of its
+    // upvalues, so it can go past 255 and takes two bytes in OpCode::Closure
+    pub fn add_upvalue(
+        &mut self,
+        index: u16,
+        kind: UpvalueCaptureType,
+    ) -> Result<u8, CompileError> {
         for (i, uv) in self.upvalues.iter().enumerate() {
-            if uv.index as u8 == index && uv.kind == kind {
+            if uv.index == index as usize && uv.kind == kind {
                 return Ok(i.try_into().unwrap());
             }
         }
@@ -121,7 +223,7 @@ impl<'src> ChunkCompiler<'src> {
         };
         self.upvalues.push(uv);
         self.function.upvalue_count = self.upvalues.len();
-        return Ok((self.upvalues.len() - 1).try_into().unwrap());
+        Ok((self.upvalues.len() - 1).try_into().unwrap())
     }
 }
 
@@ -136,6 +238,9 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             first_error: None,
             panic_mode: false,
             cc,
+            operand_start: 0,
+            operand_constants: 0,
+            parse_depth: 0,
         }
     }
 
@@ -145,15 +250,112 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
 
     fn end_scope(&mut self) {
         self.cc.scope_depth -= 1;
+        let mut captured = Vec::new();
         while !self.cc.locals.is_empty()
             && self.cc.locals.last().unwrap().depth.unwrap() > self.cc.scope_depth
         {
-            if self.cc.locals.last().unwrap().is_captured {
+            captured.push(self.cc.locals.pop().unwrap().is_captured);
+        }
+        self.emit_discards(captured);
+    }
+
+    // Gets rid of locals from the top of the stack down, given whether each
+    // one is captured. Captured locals have to be closed one at a time, but
+    // each run of the others between them goes with a single PopN.
+    fn emit_discards(&mut self, captured: Vec<bool>) {
+        let mut pops = 0;
+        for is_captured in captured {
+            if is_captured {
+                self.emit_pops(pops);
+                pops = 0;
                 self.emit_byte(OpCode::CloseUpvalue.into());
             } else {
-                self.emit_byte(OpCode::Pop.into());
+                pops += 1;
+                if pops == u8::MAX {
+                    self.emit_pops(pops);
+                    pops = 0;
+                }
+            }
+        }
+        self.emit_pops(pops);
+    }
+
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit_byte(OpCode::Pop.into()),
+            n => self.emit_bytes(OpCode::PopN.into(), n),
+        }
+    }
+
+    // Emit code to discard locals deeper than `depth` without forgetting about
+    // them, for jumping out of a scope that the compiler hasn't finished with.
+    // A local that is only captured later in the scope can still be popped
+    // here: the jump leaves the scope, so the closure that captures it can't
+    // have been created yet in this run through the scope.
+    fn discard_locals(&mut self, depth: usize) {
+        let mut captured = Vec::new();
+        for local in self.cc.locals.iter().rev() {
+            if matches!(local.depth, Some(d) if d <= depth) {
+                break;
             }
-            self.cc.locals.pop();
+            captured.push(local.is_captured);
+        }
+        self.emit_discards(captured);
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.cc.loops.push(LoopContext {
+            start,
+            scope_depth: self.cc.scope_depth,
+            break_jumps: Vec::new(),
+            loop_variables: Vec::new(),
+        });
+    }
+
+    // Give the body of a for loop its own copy of each variable declared in
+    // the initializer, so that a closure made in the body captures this
+    // iteration's value rather than the one variable shared by all of them
+    fn copy_loop_variables(&mut self, first: usize) {
+        let originals = first..self.cc.locals.len();
+        self.begin_scope();
+        for slot in originals {
+            let local = &self.cc.locals[slot];
+            let (name, mutable) = (local.name, local.mutable);
+            self.emit_local_op(OpCode::GetLocal, slot);
+            self.add_local(name);
+            self.mark_initialized();
+            let copy = self.cc.locals.len() - 1;
+            self.cc.locals[copy].mutable = mutable;
+            let lc = self.cc.loops.last_mut().unwrap();
+            lc.loop_variables.push((copy, slot));
+        }
+    }
+
+    // Before the increment runs, the loop variables catch up with whatever
+    // the body did to its copies
+    fn write_back_loop_variables(&mut self) {
+        let pairs = self.cc.loops.last().unwrap().loop_variables.clone();
+        for (copy, slot) in pairs {
+            self.emit_local_op(OpCode::GetLocal, copy);
+            self.emit_local_op(OpCode::SetLocal, slot);
+            self.emit_byte(OpCode::Pop.into());
+        }
+    }
+
+    fn emit_local_op(&mut self, op: OpCode, slot: usize) {
+        let op = match op {
+            OpCode::GetLocal if slot > u8::MAX as usize => OpCode::GetLocalLong,
+            OpCode::SetLocal if slot > u8::MAX as usize => OpCode::SetLocalLong,
+            _ => op,
+        };
+        self.emit_variable_op(op, slot as u16);
+    }
+
+    fn end_loop(&mut self) {
+        let lc = self.cc.loops.pop().unwrap();
+        for jump in lc.break_jumps {
+            self.patch_jump(jump);
         }
     }
 
@@ -195,11 +397,52 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         true
     }
 
+    // Consumes a compound assignment operator like += if there is one, returning
+    // the arithmetic instruction it stands for
+    pub fn match_compound_assignment(&mut self) -> Option<OpCode> {
+        let op = compound_assignment_op(self.current.as_ref()?.ttype)?;
+        self.advance();
+        Some(op)
+    }
+
+    pub fn match_increment(&mut self) -> Option<OpCode> {
+        let op = match self.current.as_ref()?.ttype {
+            TokenType::PlusPlus => OpCode::Add,
+            TokenType::MinusMinus => OpCode::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    // With --dump-parse, prints what the parser is about to compile, indented
+    // by how deeply it is nested in the statements and expressions around it
+    fn parse_node(&mut self, kind: fmt::Arguments, line: LineNo, f: impl FnOnce(&mut Self)) {
+        if self.vm.dump_parse {
+            let indent = "  ".repeat(self.parse_depth);
+            println!("{}{} ({})", indent, kind, self.vm.describe_line(line));
+        }
+        self.parse_depth += 1;
+        f(self);
+        self.parse_depth -= 1;
+    }
+
+    fn current_line(&self) -> LineNo {
+        self.current.as_ref().unwrap().line
+    }
+
     pub fn parse_precedence(&mut self, prec: Precedence) {
         self.advance();
         let can_assign = prec <= Precedence::Assignment;
-        match get_rule(self.previous.as_ref().unwrap().ttype).prefix {
-            Some(rule) => rule(self, can_assign),
+        let start = self.code_len();
+        let constants = self.constants_len();
+        let token = self.previous.clone().unwrap();
+        match get_rule(token.ttype).prefix {
+            Some(rule) => self.parse_node(
+                format_args!("{:?} {}", token.ttype, token.content.unwrap_or("")),
+                token.line,
+                |c| rule(c, can_assign),
+            ),
             None => {
                 self.error("Expect expression.", CompileError::ParseError);
                 return;
@@ -207,16 +450,24 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
         while prec <= get_rule(self.current.as_ref().unwrap().ttype).precedence {
             self.advance();
-            get_rule(self.previous.as_ref().unwrap().ttype)
-                .infix
-                .unwrap()(self, can_assign);
+            self.operand_start = start;
+            self.operand_constants = constants;
+            let token = self.previous.clone().unwrap();
+            let rule = get_rule(token.ttype).infix.unwrap();
+            self.parse_node(
+                format_args!("{:?} {}", token.ttype, token.content.unwrap_or("")),
+                token.line,
+                |c| rule(c, can_assign),
+            );
         }
-        if can_assign && self.match_token(TokenType::Equal) {
+        if can_assign
+            && (self.match_token(TokenType::Equal) || self.match_compound_assignment().is_some())
+        {
             self.error("Invalid assignment target.", CompileError::ParseError);
         }
     }
 
-    pub fn parse_variable(&mut self, message: &str) -> Result<Option<u8>, CompileError> {
+    pub fn parse_variable(&mut self, message: &str) -> Result<Option<u16>, CompileError> {
         self.consume(TokenType::Identifier, message);
         self.declare_variable();
         if self.cc.scope_depth > 0 {
@@ -232,8 +483,62 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         create_string(vm, name).into()
     }
 
-    pub fn identifier_constant(&mut self, name: Value) -> Result<u8, CompileError> {
-        self.get_current_chunk().add_constant(name)
+    // Names can go past the first 256 constants, since the long forms of the
+    // global opcodes take a two-byte index
+    pub fn identifier_constant(&mut self, name: Value) -> Result<u16, CompileError> {
+        let index = self.get_current_chunk().add_long_constant(name)?;
+        u16::try_from(index).map_err(|_| CompileError::TooManyConstants)
+    }
+
+    // Find the opcodes for reading and writing a variable and their operand
+    pub fn resolve_variable(&mut self, name: &str) -> Result<(OpCode, OpCode, u16), CompileError> {
+        if let Some(slot) = self.cc.resolve_local(name)? {
+            if slot > u8::MAX as u16 {
+                return Ok((OpCode::GetLocalLong, OpCode::SetLocalLong, slot));
+            }
+            return Ok((OpCode::GetLocal, OpCode::SetLocal, slot));
+        }
+        if let Some(index) = self.cc.resolve_upvalue(name)? {
+            return Ok((OpCode::GetUpvalue, OpCode::SetUpvalue, index as u16));
+        }
+        let name_val = create_string(self.vm, name).into();
+        let constant = self.identifier_constant(name_val)?;
+        if constant > u8::MAX as u16 {
+            return Ok((OpCode::GetGlobalLong, OpCode::SetGlobalLong, constant));
+        }
+        Ok((OpCode::GetGlobal, OpCode::SetGlobal, constant))
+    }
+
+    pub fn emit_variable_op(&mut self, op: OpCode, arg: u16) {
+        let slot = match op {
+            OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::DefineGlobalLong => Some(self.global_slot(arg)),
+            _ => None,
+        };
+        if arg > u8::MAX as u16 {
+            // only the long forms of the opcodes need a wider operand
+            self.emit_byte(op.into());
+            self.emit_short(arg);
+        } else {
+            self.emit_bytes(op.into(), arg as u8);
+        }
+        // a global's slot follows the constant holding its name
+        if let Some(slot) = slot {
+            self.emit_short(slot);
+        }
+    }
+
+    fn global_slot(&mut self, constant: u16) -> u16 {
+        let name = self.get_current_chunk().constants[constant as usize].clone();
+        let slot = self.vm.global_slot(name.try_into().unwrap());
+        u16::try_from(slot).unwrap_or_else(|_| {
+            self.short_error(CompileError::TooManyGlobals);
+            0
+        })
     }
 
     pub fn declare_variable(&mut self) {
@@ -261,21 +566,28 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     pub fn add_local(&mut self, name: &'src str) {
-        if self.cc.locals.len() == u8::MAX as usize + 1 {
+        if self.cc.locals.len() == u16::MAX as usize + 1 {
             self.short_error(CompileError::TooManyLocals);
             return;
         }
         let local = Local {
-            name: name,
+            name,
             depth: None,
             is_captured: false,
+            mutable: true,
         };
         self.cc.locals.push(local);
     }
 
-    pub fn define_variable(&mut self, global: Option<u8>) {
+    pub fn define_variable(&mut self, global: Option<u16>) {
         if self.cc.scope_depth == 0 {
-            self.emit_bytes(OpCode::DefineGlobal.into(), global.unwrap());
+            let constant = global.unwrap();
+            let op = if constant > u8::MAX as u16 {
+                OpCode::DefineGlobalLong
+            } else {
+                OpCode::DefineGlobal
+            };
+            self.emit_variable_op(op, constant);
         } else {
             // mark initialized, it's already sitting on the stack in the right place
             self.mark_initialized();
@@ -293,12 +605,16 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         let mut arg_count: usize = 0;
         if !self.check(TokenType::RightParen) {
             loop {
-                self.expression();
+                // the callee and the arguments before this one are on the stack
+                self.cc.temporaries += 1 + arg_count;
+                self.assignment_expression();
+                self.cc.temporaries -= 1 + arg_count;
                 if arg_count == 255 {
                     self.short_error(CompileError::TooManyArguments);
                 }
                 arg_count += 1;
-                if !self.match_token(TokenType::Comma) {
+                // a comma is allowed after the last argument, but not on its own
+                if !self.match_token(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
@@ -314,10 +630,28 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.consume(TokenType::RightBrace, "Expect '}' after block.");
     }
 
+    // A default is only evaluated when its argument is missing, by code at the
+    // start of the function that the call jumps into partway through. It can
+    // use the parameters before it, since they've all been set by then.
+    fn parameter_default(&mut self) {
+        let start = self.code_len();
+        self.cc.function.defaults.push(start);
+        let slot = (self.cc.locals.len() - 1) as u8;
+        // the parameter has its slot already, though it isn't initialized
+        self.cc.temporaries += 1;
+        self.assignment_expression();
+        self.cc.temporaries -= 1;
+        self.emit_bytes(OpCode::SetLocal.into(), slot);
+        self.emit_byte(OpCode::Pop.into());
+    }
+
     pub fn function(&mut self, function_type: FunctionType) {
         self.begin_cc(function_type);
         self.begin_scope();
-        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        match function_type {
+            FunctionType::Lambda => self.consume(TokenType::LeftParen, "Expect '(' after 'fun'."),
+            _ => self.consume(TokenType::LeftParen, "Expect '(' after function name."),
+        }
         if !self.check(TokenType::RightParen) {
             loop {
                 self.cc.function.arity += 1;
@@ -330,53 +664,234 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
                         break;
                     }
                     Ok(constant) => {
+                        if self.match_token(TokenType::Equal) {
+                            self.parameter_default();
+                        } else if !self.cc.function.defaults.is_empty() {
+                            self.short_error(CompileError::RequiredAfterDefault);
+                        }
                         self.define_variable(constant);
                     }
                 }
-                if !self.match_token(TokenType::Comma) {
+                if !self.match_token(TokenType::Comma) || self.check(TokenType::RightParen) {
                     break;
                 }
             }
         }
+        if !self.cc.function.defaults.is_empty() {
+            let body_start = self.code_len();
+            self.cc.function.defaults.push(body_start);
+        }
         self.consume(TokenType::RightParen, "Expect ')' after parameters.");
         self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         self.block();
+        self.emit_closure();
+    }
+
+    // Ends the function being compiled, and makes a closure of it in the one
+    // around it
+    fn emit_closure(&mut self) {
         let uvs = self.cc.upvalues.clone();
         let func = self.end_cc();
         let value = Value::FunctionProto(manage(self.vm, func));
-        if let Ok(constant) = self.get_current_chunk().add_constant(value) {
-            self.emit_bytes(OpCode::Closure.into(), constant);
+        if self.emit_constant_op(OpCode::Closure, OpCode::ClosureLong, value) {
             for uv in uvs {
                 self.emit_byte(match uv.kind {
                     UpvalueCaptureType::EnclosingLocal => 1,
                     UpvalueCaptureType::EnclosingUpvalue => 0,
                 });
-                self.emit_byte(uv.index.try_into().unwrap());
+                self.emit_short(uv.index as u16);
             }
-        } else {
-            let m: &str = &format!("{}", CompileError::TooManyConstants);
-            self.error(m, CompileError::TooManyConstants)
         }
     }
 
     pub fn expression(&mut self) {
+        self.parse_precedence(Precedence::Comma)
+    }
+
+    // For places where a comma separates one expression from the next, such
+    // as argument lists, rather than being the comma operator
+    pub fn assignment_expression(&mut self) {
         self.parse_precedence(Precedence::Assignment)
     }
 
     pub fn expression_statement(&mut self) {
+        let start = self.code_len();
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(OpCode::Pop.into());
+        self.discard_expression(start);
+    }
+
+    fn discard_expression(&mut self, start: usize) {
+        match self.discard_logical(start) {
+            Some(jump) => {
+                self.emit_byte(OpCode::Pop.into());
+                self.patch_jump(jump);
+            }
+            None => self.emit_byte(OpCode::Pop.into()),
+        }
+    }
+
+    // Whether the statement about to be compiled is an expression statement,
+    // going by the same tests as declaration and statement
+    fn at_expression_statement(&self) -> bool {
+        let ttype = self.current.as_ref().unwrap().ttype;
+        match ttype {
+            TokenType::Var
+            | TokenType::Const
+            | TokenType::Return
+            | TokenType::Print
+            | TokenType::If
+            | TokenType::While
+            | TokenType::For
+            | TokenType::Switch
+            | TokenType::Break
+            | TokenType::Continue => false,
+            TokenType::LeftBrace => self.is_final_block(),
+            TokenType::Fun => self.is_lambda(),
+            _ => !self.is_multiple_assignment(),
+        }
+    }
+
+    // Look ahead from the { in the current token to see if its block is the
+    // last thing in the enclosing block, in which case it's the enclosing
+    // block's value rather than a block statement
+    fn is_final_block(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut depth = 1;
+        while depth > 0 {
+            match scanner.scan_token().ttype {
+                TokenType::EOF => return false,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => depth -= 1,
+                _ => (),
+            }
+        }
+        scanner.scan_token().ttype == TokenType::RightBrace
+    }
+
+    // A block used as an expression gives the value of the expression at its
+    // end, written without a semicolon, or nil if it doesn't have one. Its
+    // locals take slots in the enclosing function, above whatever the
+    // expression around the block already has on the stack (such as the
+    // callee and earlier arguments of f({ ... })), so each of those values
+    // gets a nameless local while the block is compiled. A local whose
+    // initializer the block is in has no slot yet, so it is out of sight
+    // until the block is done.
+    pub fn block_expression(&mut self) {
+        let temporaries = std::mem::take(&mut self.cc.temporaries);
+        let locals = &self.cc.locals;
+        let unplaced = locals.iter().rev().take_while(|l| l.depth.is_none());
+        let outside = locals.len() - unplaced.count();
+        let hidden = self.cc.locals.split_off(outside);
+        self.begin_scope();
+        for _ in 0..temporaries {
+            self.add_local("");
+            self.mark_initialized();
+        }
+        // If the block declares locals, a slot for its value goes in below
+        // them, so that they can be discarded from the top of the stack (and
+        // closed in place if they were captured) once the value is in it
+        let value_slot = self.cc.locals.len();
+        let mut has_value = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if !self.at_expression_statement() {
+                if self.at_declaration() && self.cc.locals.len() == value_slot {
+                    self.emit_byte(OpCode::Nil.into());
+                    self.add_local("");
+                    self.mark_initialized();
+                }
+                self.declaration();
+                continue;
+            }
+            let start = self.code_len();
+            self.expression();
+            if self.check(TokenType::RightBrace) {
+                has_value = true;
+                break;
+            }
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+            self.discard_expression(start);
+            if self.panic_mode {
+                self.synchronize();
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        if self.cc.locals.len() == value_slot {
+            if !has_value {
+                self.emit_byte(OpCode::Nil.into());
+            }
+        } else {
+            let mut captured = Vec::new();
+            if has_value {
+                self.emit_local_op(OpCode::SetLocal, value_slot);
+                captured.push(false);
+            }
+            let locals = &self.cc.locals[value_slot + 1..];
+            captured.extend(locals.iter().rev().map(|l| l.is_captured));
+            self.emit_discards(captured);
+        }
+        self.cc.scope_depth -= 1;
+        self.cc.locals.truncate(outside);
+        self.cc.locals.extend(hidden);
+        self.cc.temporaries = temporaries;
+    }
+
+    // Whether the current token starts a declaration of a named variable,
+    // function or constant
+    fn at_declaration(&self) -> bool {
+        let ttype = self.current.as_ref().unwrap().ttype;
+        matches!(ttype, TokenType::Var | TokenType::Const)
+            || (ttype == TokenType::Fun && !self.is_lambda())
+    }
+
+    pub fn record_call(&mut self) {
+        self.cc.last_call_end = Some(self.code_len());
+    }
+
+    pub fn record_logical(
+        &mut self,
+        op: OpCode,
+        left_start: usize,
+        jump: usize,
+        right_start: usize,
+    ) {
+        self.cc.last_logical = Some(LogicalJump {
+            op,
+            left_start,
+            jump,
+            right_start,
+            end: self.code_len(),
+        });
+    }
+
+    // If the statement starting at start is nothing but an `and` or `or`, turn
+    // its jumps into a single jump that pops the left operand, so that only
+    // the right operand is left to pop. Returns the jump that needs to be
+    // patched to land after that pop.
+    fn discard_logical(&mut self, start: usize) -> Option<usize> {
+        let logical = self.cc.last_logical.take()?;
+        if logical.left_start != start || logical.end != self.code_len() {
+            return None;
+        }
+        let chunk = self.get_current_chunk();
+        chunk.code[logical.jump] = logical.op.into();
+        chunk.remove_code(logical.jump + 3, logical.right_start);
+        Some(logical.jump + 1)
     }
 
     pub fn return_statement(&mut self) {
         match self.cc.function_type {
-            FunctionType::Function => {
+            FunctionType::Function | FunctionType::Lambda => {
                 if self.match_token(TokenType::Semicolon) {
                     self.emit_return()
                 } else {
                     self.expression();
                     self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+                    // clox keeps a frame for every call, which shows in backtraces
+                    let end = self.code_len();
+                    if self.cc.last_call_end == Some(end) && !self.vm.compat_clox {
+                        self.get_current_chunk().code[end - 2] = OpCode::TailCall.into();
+                    }
                     self.emit_byte(OpCode::Return.into());
                 }
             }
@@ -384,6 +899,88 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
     }
 
+    // Look ahead without consuming anything to see if the identifier in the
+    // current token starts a list of targets like `a, b = ...`
+    fn is_multiple_assignment(&self) -> bool {
+        if !matches!(&self.current, Some(t) if t.ttype == TokenType::Identifier) {
+            return false;
+        }
+        let mut scanner = self.scanner.clone();
+        let mut targets = 1;
+        loop {
+            match scanner.scan_token().ttype {
+                TokenType::Comma => (),
+                TokenType::Equal => return targets > 1,
+                _ => return false,
+            }
+            if scanner.scan_token().ttype != TokenType::Identifier {
+                return false;
+            }
+            targets += 1;
+        }
+    }
+
+    // Finishes the tuple in a grouping, once its first item and the comma
+    // after it have been compiled. As with arguments, a comma after the last
+    // item is allowed, which is how to write a tuple of one item.
+    pub fn finish_tuple(&mut self) {
+        let mut count: usize = 1;
+        while !self.check(TokenType::RightParen) {
+            self.cc.temporaries += count;
+            self.assignment_expression();
+            self.cc.temporaries -= count;
+            if count == 255 {
+                self.short_error(CompileError::TooManyTupleItems);
+            }
+            count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after tuple items.");
+        self.emit_bytes(OpCode::BuildTuple.into(), count as u8);
+    }
+
+    // All of the values are evaluated before any of them are assigned, which
+    // lets you write a swap as `a, b = b, a;`
+    pub fn multiple_assignment(&mut self) {
+        let mut targets = Vec::new();
+        loop {
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            let name = self.previous.as_ref().unwrap().content.unwrap();
+            if self.is_const(name) {
+                self.short_error(CompileError::AssignToConst);
+            }
+            match self.resolve_variable(name) {
+                Err(ce) => self.short_error(ce),
+                Ok((_, set_op, arg)) => targets.push((set_op, arg)),
+            }
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::Equal, "Expect '=' after assignment targets.");
+        let mut value_count = 0;
+        loop {
+            self.cc.temporaries += value_count;
+            self.assignment_expression();
+            self.cc.temporaries -= value_count;
+            value_count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        if value_count != targets.len() {
+            self.short_error(CompileError::AssignmentMismatch(targets.len(), value_count));
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after values.");
+        // the last value is on top of the stack, so assign in reverse order
+        for (set_op, arg) in targets.into_iter().rev() {
+            self.emit_variable_op(set_op, arg);
+            self.emit_byte(OpCode::Pop.into());
+        }
+    }
+
     pub fn print_statement(&mut self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
@@ -394,16 +991,20 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
-        self.emit_byte(OpCode::Pop.into());
+        // The jump pops the condition whichever way it goes, so neither branch
+        // starts with a Pop and an if without an else has nothing to jump over.
+        // An else if is just an if statement as the else branch, so each then
+        // branch in a chain jumps straight to the end of the whole chain.
+        let then_jump = self.emit_jump(OpCode::PopJumpIfFalse);
         self.statement();
-        let else_jump = self.emit_jump(OpCode::Jump);
-        self.patch_jump(then_jump);
-        self.emit_byte(OpCode::Pop.into());
         if self.match_token(TokenType::Else) {
+            let else_jump = self.emit_jump(OpCode::Jump);
+            self.patch_jump(then_jump);
             self.statement();
+            self.patch_jump(else_jump);
+        } else {
+            self.patch_jump(then_jump);
         }
-        self.patch_jump(else_jump);
     }
 
     pub fn while_statement(&mut self) {
@@ -413,18 +1014,118 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.consume(TokenType::RightParen, "Expect ')' after condition.");
         let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
         self.emit_byte(OpCode::Pop.into());
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
     }
 
+    pub fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after value.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+        // the subject stays on the stack as a hidden local so that the case
+        // bodies can declare locals of their own in the slots above it
+        self.begin_scope();
+        self.add_local("");
+        self.mark_initialized();
+        let mut end_jumps = Vec::new();
+        let mut seen_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if self.match_token(TokenType::Case) {
+                if seen_default {
+                    self.error(
+                        "Can't have a case after the default case.",
+                        CompileError::ParseError,
+                    );
+                }
+                self.emit_byte(OpCode::Dup.into());
+                self.cc.temporaries += 1;
+                self.expression();
+                self.cc.temporaries -= 1;
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+                self.emit_byte(OpCode::Equal.into());
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop.into());
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+                self.emit_byte(OpCode::Pop.into());
+            } else if self.match_token(TokenType::Default) {
+                if seen_default {
+                    self.error(
+                        "Can't have more than one default case.",
+                        CompileError::ParseError,
+                    );
+                }
+                seen_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.", CompileError::ParseError);
+                self.case_body();
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::EOF)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    pub fn break_statement(&mut self) {
+        match self.cc.loops.last() {
+            None => self.short_error(CompileError::BreakOutsideLoop),
+            Some(lc) => {
+                self.discard_locals(lc.scope_depth);
+                let jump = self.emit_jump(OpCode::Jump);
+                self.cc.loops.last_mut().unwrap().break_jumps.push(jump);
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+    }
+
+    pub fn continue_statement(&mut self) {
+        match self.cc.loops.last() {
+            None => self.short_error(CompileError::ContinueOutsideLoop),
+            Some(lc) => {
+                let (start, depth) = (lc.start, lc.scope_depth);
+                self.write_back_loop_variables();
+                self.discard_locals(depth);
+                self.emit_loop(start);
+            }
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+    }
+
+    // Normally, as in clox, there is one loop variable for the whole loop. With
+    // --fresh-loop-variables, the body works on a copy of it that is made
+    // afresh for each iteration, and the condition and increment see whatever
+    // the body left in its copy (even after a continue).
     pub fn for_statement(&mut self) {
         self.begin_scope();
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let first_variable = self.cc.locals.len();
+        let mut fresh_variables = false;
         if self.match_token(TokenType::Semicolon) {
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+            fresh_variables = self.vm.fresh_loop_variables;
         } else {
             self.expression_statement();
         }
@@ -446,20 +1147,45 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
-        self.statement();
+        self.begin_loop(loop_start);
+        if fresh_variables {
+            self.copy_loop_variables(first_variable);
+            self.statement();
+            self.write_back_loop_variables();
+            self.end_scope();
+        } else {
+            self.statement();
+        }
         self.emit_loop(loop_start);
         if let Some(exit_jump) = exit_jump {
             self.patch_jump(exit_jump);
             self.emit_byte(OpCode::Pop.into());
         }
+        self.end_loop();
         self.end_scope();
     }
 
+    // Look ahead to see if the `fun` in the current token starts a lambda
+    // rather than a declaration, as in `fun () {...}();`
+    fn is_lambda(&self) -> bool {
+        matches!(&self.current, Some(t) if t.ttype == TokenType::Fun)
+            && self.scanner.clone().scan_token().ttype == TokenType::LeftParen
+    }
+
     pub fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
-            self.fun_declaration();
+        let line = self.current_line();
+        if self.is_lambda() {
+            self.statement();
+        } else if self.match_token(TokenType::Fun) {
+            self.parse_node(format_args!("fun declaration"), line, Self::fun_declaration);
         } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
+            self.parse_node(format_args!("var declaration"), line, Self::var_declaration);
+        } else if self.match_token(TokenType::Const) {
+            self.parse_node(
+                format_args!("const declaration"),
+                line,
+                Self::const_declaration,
+            );
         } else {
             self.statement();
         }
@@ -472,6 +1198,7 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         match self.parse_variable("Expect variable name.") {
             Err(e) => self.error(&format!("{}", e), e),
             Ok(global) => {
+                self.check_global_redeclaration();
                 self.mark_initialized();
                 self.function(FunctionType::Function);
                 self.define_variable(global);
@@ -479,12 +1206,62 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
     }
 
+    // Whether the name refers to a constant, found the same way as
+    // resolve_variable() would find it. Constants are only enforced here, at
+    // compile time, so code compiled before a global constant is declared
+    // (such as a function earlier in the same script) can still assign to it.
+    pub fn is_const(&self, name: &str) -> bool {
+        let mut cc = Some(&self.cc);
+        while let Some(c) = cc {
+            if let Some(local) = c.locals.iter().rev().find(|l| l.name == name) {
+                return !local.mutable;
+            }
+            cc = c.enclosing.as_deref();
+        }
+        self.vm.const_globals.contains(name)
+    }
+
+    // Redeclaring a global is allowed, but not if it would replace a constant
+    fn check_global_redeclaration(&mut self) {
+        let name = self.previous.as_ref().unwrap().content.unwrap();
+        if self.cc.scope_depth == 0 && self.vm.const_globals.contains(name) {
+            self.short_error(CompileError::AssignToConst);
+        }
+    }
+
+    pub fn const_declaration(&mut self) {
+        match self.parse_variable("Expect constant name.") {
+            Err(e) => self.error(&format!("{}", e), e),
+            Ok(global) => {
+                self.check_global_redeclaration();
+                let name = self.previous.as_ref().unwrap().content.unwrap();
+                self.consume(TokenType::Equal, "Expect '=' after constant name.");
+                self.assignment_expression();
+                self.consume(
+                    TokenType::Semicolon,
+                    "Expect ';' after constant declaration.",
+                );
+                if self.cc.scope_depth == 0 {
+                    self.vm.const_globals.insert(name.to_owned());
+                } else {
+                    self.cc.locals.last_mut().unwrap().mutable = false;
+                }
+                self.define_variable(global);
+            }
+        }
+    }
+
     pub fn var_declaration(&mut self) {
+        if self.match_token(TokenType::LeftParen) {
+            self.destructuring_declaration();
+            return;
+        }
         match self.parse_variable("Expect variable name.") {
             Err(e) => self.error(&format!("{}", e), e),
             Ok(global) => {
+                self.check_global_redeclaration();
                 if self.match_token(TokenType::Equal) {
-                    self.expression();
+                    self.assignment_expression();
                 } else {
                     self.emit_byte(OpCode::Nil.into());
                 }
@@ -497,6 +1274,49 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         }
     }
 
+    // var (x, y) = pair; declares each name and then unpacks the tuple into
+    // them, which is a runtime error if it has a different number of items
+    fn destructuring_declaration(&mut self) {
+        let mut globals = Vec::new();
+        loop {
+            match self.parse_variable("Expect variable name.") {
+                Err(e) => self.error(&format!("{}", e), e),
+                Ok(global) => {
+                    self.check_global_redeclaration();
+                    globals.push(global);
+                }
+            }
+            if globals.len() > 255 {
+                self.short_error(CompileError::TooManyTupleItems);
+            }
+            if !self.match_token(TokenType::Comma) || self.check(TokenType::RightParen) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after variable names.");
+        self.consume(TokenType::Equal, "Expect '=' after variable names.");
+        self.assignment_expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        let count = globals.len();
+        self.emit_bytes(OpCode::Unpack.into(), count as u8);
+        if self.cc.scope_depth > 0 {
+            // the items are already in the locals' slots, so mark them all
+            let depth = Some(self.cc.scope_depth);
+            let first = self.cc.locals.len().saturating_sub(count);
+            for local in &mut self.cc.locals[first..] {
+                local.depth = depth;
+            }
+        } else {
+            // the last item is on top of the stack
+            for global in globals.into_iter().rev() {
+                self.define_variable(global);
+            }
+        }
+    }
+
     pub fn synchronize(&mut self) {
         self.panic_mode = false;
         while self.current.as_ref().unwrap().ttype != TokenType::EOF {
@@ -507,9 +1327,11 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Switch
                 | TokenType::Print
                 | TokenType::Return => return,
                 _ => (),
@@ -519,30 +1341,66 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     pub fn statement(&mut self) {
+        let line = self.current_line();
         if self.match_token(TokenType::Return) {
-            self.return_statement();
+            self.parse_node(
+                format_args!("return statement"),
+                line,
+                Self::return_statement,
+            );
         } else if self.match_token(TokenType::Print) {
-            self.print_statement();
+            self.parse_node(format_args!("print statement"), line, Self::print_statement);
         } else if self.match_token(TokenType::If) {
-            self.if_statement();
+            self.parse_node(format_args!("if statement"), line, Self::if_statement);
         } else if self.match_token(TokenType::While) {
-            self.while_statement();
+            self.parse_node(format_args!("while statement"), line, Self::while_statement);
         } else if self.match_token(TokenType::For) {
-            self.for_statement();
+            self.parse_node(format_args!("for statement"), line, Self::for_statement);
+        } else if self.match_token(TokenType::Switch) {
+            self.parse_node(
+                format_args!("switch statement"),
+                line,
+                Self::switch_statement,
+            );
+        } else if self.match_token(TokenType::Break) {
+            self.parse_node(format_args!("break statement"), line, Self::break_statement);
+        } else if self.match_token(TokenType::Continue) {
+            self.parse_node(
+                format_args!("continue statement"),
+                line,
+                Self::continue_statement,
+            );
         } else if self.match_token(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block();
-            self.end_scope();
+            self.parse_node(format_args!("block"), line, |c| {
+                c.begin_scope();
+                c.block();
+                c.end_scope();
+            });
+        } else if self.is_multiple_assignment() {
+            self.parse_node(
+                format_args!("multiple assignment"),
+                line,
+                Self::multiple_assignment,
+            );
         } else {
-            self.expression_statement();
+            self.parse_node(
+                format_args!("expression statement"),
+                line,
+                Self::expression_statement,
+            );
         }
     }
 
-    fn error_at_current(&mut self, message: &str, ce: CompileError) {
+    pub(crate) fn error_at_current(&mut self, message: &str, ce: CompileError) {
         if self.panic_mode {
             return;
         }
-        report_error(message, self.current.as_ref().unwrap());
+        report_error(
+            self.vm,
+            message,
+            self.current.as_ref().unwrap(),
+            self.scanner.source(),
+        );
         self.first_error = self.first_error.or(Some(ce));
         self.panic_mode = true
     }
@@ -555,7 +1413,12 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         if self.panic_mode {
             return;
         }
-        report_error(message, self.previous.as_ref().unwrap());
+        report_error(
+            self.vm,
+            message,
+            self.previous.as_ref().unwrap(),
+            self.scanner.source(),
+        );
         self.first_error = self.first_error.or(Some(ce));
         self.panic_mode = true
     }
@@ -564,8 +1427,103 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.error(&ce.to_string(), ce);
     }
 
+    // For errors found after a construct has been parsed successfully, where the
+    // parser isn't lost and entering panic mode would only cause the following
+    // tokens to be skipped and spurious errors to be reported
+    pub(crate) fn error_without_panic(&mut self, ce: CompileError) {
+        if self.panic_mode {
+            return;
+        }
+        report_error(
+            self.vm,
+            &ce.to_string(),
+            self.previous.as_ref().unwrap(),
+            self.scanner.source(),
+        );
+        self.first_error = self.first_error.or(Some(ce));
+    }
+
     fn get_current_chunk(&mut self) -> &mut Chunk {
-        return &mut self.cc.function.chunk;
+        &mut self.cc.function.chunk
+    }
+
+    // Decodes a Constant or ConstantLong instruction, returning the constant
+    // and the offset of the next instruction
+    fn constant_at(&self, offset: usize) -> Option<(Value, usize)> {
+        let chunk = &self.cc.function.chunk;
+        match OpCode::try_from(*chunk.code.get(offset)?) {
+            Ok(OpCode::Constant) => {
+                let index = *chunk.code.get(offset + 1)? as usize;
+                Some((chunk.constants[index].clone(), offset + 2))
+            }
+            Ok(OpCode::ConstantLong) => {
+                let bytes = chunk.code.get(offset + 1..offset + 4)?;
+                let index =
+                    (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize;
+                Some((chunk.constants[index].clone(), offset + 4))
+            }
+            _ => None,
+        }
+    }
+
+    // If the code from start is nothing but constants and then the operator
+    // that was just emitted, work out the result now and load that instead.
+    // Anything that could behave differently at runtime is left alone: a zero
+    // divisor (which --strict-division makes an error) and results that
+    // aren't finite. Constants added to the pool from constants on can only
+    // be the operands, so they go too.
+    pub fn fold_constants(&mut self, start: usize, constants: usize) {
+        let end = self.code_len() - 1;
+        let op = OpCode::try_from(self.cc.function.chunk.code[end]);
+        let mut operands = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            match self.constant_at(offset) {
+                Some((value, next)) => {
+                    operands.push(value);
+                    offset = next;
+                }
+                None => return,
+            }
+        }
+        let result = match (op, operands.as_slice()) {
+            (Ok(OpCode::Negate), [Value::Number(a)]) => Value::Number(-a),
+            (Ok(op), [Value::Number(a), Value::Number(b)]) => {
+                let n = match op {
+                    OpCode::Add => a + b,
+                    OpCode::Subtract => a - b,
+                    OpCode::Multiply => a * b,
+                    OpCode::Divide if *b != 0.0 => a / b,
+                    OpCode::Modulo if *b != 0.0 => a % b,
+                    _ => return,
+                };
+                if !n.is_finite() {
+                    return;
+                }
+                Value::Number(n)
+            }
+            (Ok(OpCode::Add), [Value::String(a), Value::String(b)]) => {
+                let s = format!(
+                    "{}{}",
+                    a.upgrade().unwrap().content,
+                    b.upgrade().unwrap().content
+                );
+                create_string(self.vm, &s).into()
+            }
+            _ => return,
+        };
+        let chunk = self.get_current_chunk();
+        chunk.remove_code(start, end + 1);
+        chunk.truncate_constants(constants);
+        self.emit_constant(result);
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.cc.function.chunk.code.len()
+    }
+
+    pub fn constants_len(&self) -> usize {
+        self.cc.function.chunk.constants.len()
     }
 
     pub fn emit_byte(&mut self, byte: u8) {
@@ -578,6 +1536,15 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.emit_byte(byte2);
     }
 
+    pub fn emit_short(&mut self, short: u16) {
+        self.emit_bytes(((short >> 8) & 0xff) as u8, (short & 0xff) as u8);
+    }
+
+    // Swap the top two values on the stack: [a, b] becomes [b, a]
+    pub fn emit_swap(&mut self) {
+        self.emit_byte(OpCode::Swap.into());
+    }
+
     pub fn emit_return(&mut self) {
         self.emit_bytes(OpCode::Nil.into(), OpCode::Return.into());
     }
@@ -593,7 +1560,9 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         let code = &mut self.get_current_chunk().code;
         let jump = code.len() - offset - 2;
         if jump > u16::MAX as usize {
-            self.short_error(CompileError::TooFarToJump)
+            // leave the placeholder operand in place; the chunk will never be
+            // run because the compiler won't return it
+            self.error_without_panic(CompileError::TooFarToJump)
         } else {
             code[offset] = ((jump >> 8) & 0xff) as u8;
             code[offset + 1] = (jump & 0xff) as u8;
@@ -604,7 +1573,9 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         self.emit_byte(OpCode::Loop.into());
         let jump = self.get_current_chunk().code.len() - loop_start + 2;
         if jump > u16::MAX as usize {
-            self.short_error(CompileError::TooFarToLoop)
+            // still emit a (placeholder) operand so the chunk stays well-formed
+            self.error_without_panic(CompileError::TooFarToLoop);
+            self.emit_bytes(0xff_u8, 0xff_u8);
         } else {
             self.emit_byte(((jump >> 8) & 0xff) as u8);
             self.emit_byte((jump & 0xff) as u8);
@@ -616,11 +1587,26 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
     }
 
     pub fn emit_constant(&mut self, value: Value) {
-        if let Ok(constant) = self.get_current_chunk().add_constant(value) {
-            self.emit_bytes(OpCode::Constant.into(), constant)
+        self.emit_constant_op(OpCode::Constant, OpCode::ConstantLong, value);
+    }
+
+    // Emits an instruction that takes a constant, or its long form with a
+    // 24-bit operand once the constant's index doesn't fit in a byte. Returns
+    // false if the pool is full.
+    fn emit_constant_op(&mut self, op: OpCode, long_op: OpCode, value: Value) -> bool {
+        if let Ok(constant) = self.get_current_chunk().add_long_constant(value) {
+            if constant <= u8::MAX as usize {
+                self.emit_bytes(op.into(), constant as u8)
+            } else {
+                self.emit_byte(long_op.into());
+                self.emit_byte(((constant >> 16) & 0xff) as u8);
+                self.emit_bytes(((constant >> 8) & 0xff) as u8, (constant & 0xff) as u8);
+            }
+            true
         } else {
             let m: &str = &format!("{}", CompileError::TooManyConstants);
-            self.error(m, CompileError::TooManyConstants)
+            self.error(m, CompileError::TooManyConstants);
+            false
         }
     }
 
@@ -629,19 +1615,20 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
         let old_cc = std::mem::replace(&mut self.cc, new_cc);
         self.cc.enclosing = Some(Box::new(old_cc));
 
-        let name = self.previous.as_ref().unwrap().content.unwrap().to_owned();
-        self.cc.function.name = Some(create_string(self.vm, &name));
+        if let FunctionType::Lambda = function_type {
+            self.cc.function.anonymous = true;
+        } else {
+            let name = self.previous.as_ref().unwrap().content.unwrap().to_owned();
+            self.cc.function.name = Some(create_string(self.vm, &name));
+        }
     }
 
     fn end_cc(&mut self) -> Function {
         // This is inconsistent with end() regarding how it handles errors
         self.emit_return();
-        #[cfg(feature = "dump")]
-        {
-            if let None = self.first_error {
-                let s = format_function_name(&self.cc.function);
-                crate::dis::disassemble_chunk(&self.get_current_chunk(), &s)
-            }
+        if (cfg!(feature = "dump") || self.vm.dump_chunks) && self.first_error.is_none() {
+            let s = format_function_name(&self.cc.function);
+            crate::dis::disassemble_chunk(self.get_current_chunk(), &s)
         }
         let new_cc = *self.cc.enclosing.take().unwrap();
         let old_cc = std::mem::replace(&mut self.cc, new_cc);
@@ -650,12 +1637,9 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
 
     fn end(mut self) -> CompilerResult {
         self.emit_return();
-        #[cfg(feature = "dump")]
-        {
-            if let None = self.first_error {
-                let s = format_function_name(&self.cc.function);
-                crate::dis::disassemble_chunk(&self.get_current_chunk(), &s)
-            }
+        if (cfg!(feature = "dump") || self.vm.dump_chunks) && self.first_error.is_none() {
+            let s = format_function_name(&self.cc.function);
+            crate::dis::disassemble_chunk(self.get_current_chunk(), &s)
         }
         match self.first_error {
             Some(e) => Err(e),
@@ -665,12 +1649,238 @@ impl<'src, 'vm> Compiler<'src, 'vm> {
 }
 
 pub(crate) fn compile(source: &str, vm: &mut VM) -> CompilerResult {
-    let scanner = Scanner::new(source);
+    // a script that fails to compile never runs, so its constants are never
+    // declared
+    let const_globals = vm.const_globals.clone();
+    let scanner = match vm.compat_clox {
+        true => Scanner::new(source).without_increments(),
+        false => Scanner::new(source),
+    };
     let mut compiler = Compiler::new(scanner, vm);
     compiler.advance();
     while !compiler.match_token(TokenType::EOF) {
         compiler.declaration();
     }
     compiler.consume(TokenType::EOF, "Expect end of expression.");
-    compiler.end()
+    let result = compiler.end();
+    if result.is_err() {
+        vm.const_globals = const_globals;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disassemble(source: &str) -> String {
+        let mut vm = VM::new();
+        compile(source, &mut vm).unwrap().disassemble()
+    }
+
+    #[test]
+    fn folded_operands_leave_the_pool() {
+        let mut vm = VM::new();
+        let function = compile("print 2 + 3 * 4;", &mut vm).unwrap();
+        assert_eq!(
+            function.disassemble(),
+            "\
+== <script> ==
+    1 0000 CONSTANT         0    14
+    | 0002 PRINT
+    | 0003 NIL
+    | 0004 RETURN
+"
+        );
+        assert_eq!(function.chunk.constants.len(), 1);
+    }
+
+    #[test]
+    fn constants_used_before_folding_stay() {
+        assert_eq!(
+            disassemble("print 3;\nprint 3 + 4;\nprint -5;"),
+            "\
+== <script> ==
+    1 0000 CONSTANT         0    3
+    | 0002 PRINT
+    2 0003 CONSTANT         1    7
+    | 0005 PRINT
+    3 0006 CONSTANT         2    -5
+    | 0008 PRINT
+    | 0009 NIL
+    | 0010 RETURN
+"
+        );
+    }
+
+    #[test]
+    fn instructions_are_counted_not_bytes() {
+        let mut vm = VM::new();
+        let function = compile("var a = 1;\nprint a + a;", &mut vm).unwrap();
+        assert_eq!(
+            function.disassemble(),
+            "\
+== <script> ==
+    1 0000 CONSTANT         1    1
+    | 0002 DEFINE_GLOBAL    0    \"a\" (slot 23)
+    2 0006 GET_GLOBAL       0    \"a\" (slot 23)
+    | 0010 GET_GLOBAL       0    \"a\" (slot 23)
+    | 0014 ADD
+    | 0015 PRINT
+    | 0016 NIL
+    | 0017 RETURN
+"
+        );
+        assert_eq!(function.instruction_count(), 8);
+        assert_eq!(function.chunk.code.len(), 18);
+    }
+
+    #[test]
+    fn locals_leaving_scope_are_popped_together() {
+        assert_eq!(
+            disassemble("{ var a = 1; var b = 2; var c = 3; var d = 4; var e = 5; }"),
+            "\
+== <script> ==
+    1 0000 CONSTANT         0    1
+    | 0002 CONSTANT         1    2
+    | 0004 CONSTANT         2    3
+    | 0006 CONSTANT         3    4
+    | 0008 CONSTANT         4    5
+    | 0010 POP_N            5   
+    | 0012 NIL
+    | 0013 RETURN
+"
+        );
+    }
+
+    #[test]
+    fn captured_locals_split_the_pops() {
+        let mut vm = VM::new();
+        let function = compile(
+            "{ var a = 1; var b = 2; fun f() { return b; } var d = 4; var e = 5; }",
+            &mut vm,
+        )
+        .unwrap();
+        assert!(function.disassemble().ends_with(
+            "\
+    | 0013 POP_N            3   
+    | 0015 CLOSE_UPVALUE
+    | 0016 POP
+    | 0017 NIL
+    | 0018 RETURN
+"
+        ));
+    }
+
+    // every arm jumps straight to the end rather than to the next arm's jump
+    #[test]
+    fn else_if_chain_jumps_to_the_end() {
+        assert_eq!(
+            disassemble(
+                "var a = 1;\nif (a == 1) print 1;\nelse if (a == 2) print 2;\nelse print 3;"
+            ),
+            "\
+== <script> ==
+    1 0000 CONSTANT         1    1
+    | 0002 DEFINE_GLOBAL    0    \"a\" (slot 23)
+    2 0006 GET_GLOBAL       0    \"a\" (slot 23)
+    | 0010 CONSTANT         1    1
+    | 0012 EQUAL
+    | 0013 POP_JUMP_IF_FALSE 6    -> 22  
+    | 0016 CONSTANT         1    1
+    | 0018 PRINT
+    3 0019 JUMP             19   -> 41  
+    | 0022 GET_GLOBAL       0    \"a\" (slot 23)
+    | 0026 CONSTANT         2    2
+    | 0028 EQUAL
+    | 0029 POP_JUMP_IF_FALSE 6    -> 38  
+    | 0032 CONSTANT         2    2
+    | 0034 PRINT
+    4 0035 JUMP             3    -> 41  
+    | 0038 CONSTANT         3    3
+    | 0040 PRINT
+    | 0041 NIL
+    | 0042 RETURN
+"
+        );
+    }
+
+    // the value goes in a slot below the block's locals, above the 1 that is
+    // already on the stack, and there's no function or call
+    #[test]
+    fn block_expression_is_compiled_inline() {
+        assert_eq!(
+            disassemble("print 1 + { var a = 2; a * 3 };"),
+            "\
+== <script> ==
+    1 0000 CONSTANT         0    1
+    | 0002 NIL
+    | 0003 CONSTANT         1    2
+    | 0005 GET_LOCAL        3   
+    | 0007 CONSTANT         2    3
+    | 0009 MULTIPLY
+    | 0010 SET_LOCAL        2   
+    | 0012 POP_N            2   
+    | 0014 ADD
+    | 0015 PRINT
+    | 0016 NIL
+    | 0017 RETURN
+"
+        );
+    }
+
+    // the jump operands are left as placeholders, so the compiler mustn't
+    // hand back a chunk, even when the error is inside a function body
+    #[test]
+    fn oversized_jumps_are_compile_errors() {
+        let body = "x = x + 1;".repeat(10_000);
+        let cases = [
+            (
+                format!("var x = 0; if (x) {{ {} }}", body),
+                "Too much code to jump over.",
+            ),
+            (
+                format!("var x = 0; while (x) {{ {} }}", body),
+                "Loop body too large.",
+            ),
+            (
+                format!("fun f() {{ var x = 0; if (x) {{ {} }} }}", body),
+                "Too much code to jump over.",
+            ),
+            (
+                format!("fun f() {{ var x = 0; while (x) {{ {} }} }} print 1;", body),
+                "Loop body too large.",
+            ),
+        ];
+        for (source, error) in cases.iter() {
+            let mut vm = VM::new();
+            let result = compile(source, &mut vm).err().map(|e| e.to_string());
+            assert_eq!(result.as_deref(), Some(*error));
+        }
+    }
+
+    // when the value of an `and` or `or` is thrown away there's a single
+    // jump that pops the left operand, instead of a JUMP_IF_FALSE and a POP
+    #[test]
+    fn discarded_logicals_pop_as_they_jump() {
+        assert_eq!(
+            disassemble("{ var a = true; var b = 1; a and b; a or b; }"),
+            "\
+== <script> ==
+    1 0000 TRUE
+    | 0001 CONSTANT         0    1
+    | 0003 GET_LOCAL        1   
+    | 0005 POP_JUMP_IF_FALSE 3    -> 11  
+    | 0008 GET_LOCAL        2   
+    | 0010 POP
+    | 0011 GET_LOCAL        1   
+    | 0013 POP_JUMP_IF_TRUE 3    -> 19  
+    | 0016 GET_LOCAL        2   
+    | 0018 POP
+    | 0019 POP_N            2   
+    | 0021 NIL
+    | 0022 RETURN
+"
+        );
+    }
 }
@@ -1,103 +1,204 @@
 use crate::{value::Value, Chunk, OpCode, TracingIP};
 use std::convert::TryFrom;
+use std::fmt::{self, Write};
 
 #[allow(dead_code)]
 pub(crate) fn disassemble_instruction(ip: &mut TracingIP) {
+    let mut s = String::new();
+    write_instruction(&mut s, ip).unwrap();
+    print!("{}", s);
+}
+
+pub(crate) fn write_instruction(out: &mut impl Write, ip: &mut TracingIP) -> fmt::Result {
     if ip.is_line_start {
-        print!("{:5} {:04} ", ip.line.unwrap(), ip.offset)
+        write!(out, "{:5} {:04} ", ip.line.unwrap(), ip.offset)?;
     } else {
-        print!("    | {:04} ", ip.offset)
+        write!(out, "    | {:04} ", ip.offset)?;
     }
     let byte = ip.read();
     match OpCode::try_from(byte) {
         Ok(instruction) => match instruction {
-            OpCode::Constant => constant_instruction("CONSTANT", ip),
-            OpCode::Nil => simple_instruction("NIL"),
-            OpCode::True => simple_instruction("TRUE"),
-            OpCode::False => simple_instruction("FALSE"),
-            OpCode::Equal => simple_instruction("EQUAL"),
-            OpCode::Greater => simple_instruction("GREATER"),
-            OpCode::Less => simple_instruction("LESS"),
-            OpCode::Negate => simple_instruction("NEGATE"),
-            OpCode::Add => simple_instruction("ADD"),
-            OpCode::Subtract => simple_instruction("SUBTRACT"),
-            OpCode::Multiply => simple_instruction("MULTIPLY"),
-            OpCode::Divide => simple_instruction("DIVIDE"),
-            OpCode::Not => simple_instruction("NOT"),
-            OpCode::Print => simple_instruction("PRINT"),
-            OpCode::Jump => jump_instruction("JUMP", ip, 1),
-            OpCode::JumpIfFalse => jump_instruction("JUMP_IF_FALSE", ip, 1),
-            OpCode::Loop => jump_instruction("LOOP", ip, -1),
-            OpCode::Call => byte_instruction("CALL", ip),
-            OpCode::Closure => {
-                let constant_index = ip.read();
-                let constant = &ip.chunk.constants[constant_index as usize];
-                println!("{:<16} {:<4} {}", "CLOSURE", constant_index, constant);
-                match constant {
-                    Value::FunctionProto(f) => {
-                        for _ in 0..(f.upgrade().unwrap().content.upvalue_count) {
-                            print!("    | {:04} ", ip.offset);
-                            let is_local = ip.read();
-                            let index = ip.read();
-                            let text = match is_local {
-                                0 => "upvalue",
-                                _ => "local",
-                            };
-                            println!("|                {} {}", text, index);
-                        }
-                    }
-                    _ => {
-                        unreachable!();
-                    }
-                };
-            }
-            OpCode::CloseUpvalue => simple_instruction("CLOSE_UPVALUE"),
-            OpCode::Pop => simple_instruction("POP"),
-            OpCode::GetLocal => byte_instruction("GET_LOCAL", ip),
-            OpCode::SetLocal => byte_instruction("SET_LOCAL", ip),
-            OpCode::GetGlobal => constant_instruction("GET_GLOBAL", ip),
-            OpCode::DefineGlobal => constant_instruction("DEFINE_GLOBAL", ip),
-            OpCode::SetGlobal => constant_instruction("SET_GLOBAL", ip),
-            OpCode::GetUpvalue => byte_instruction("GET_UPVALUE", ip),
-            OpCode::SetUpvalue => byte_instruction("SET_UPVALUE", ip),
-            OpCode::Return => simple_instruction("RETURN"),
+            OpCode::Constant => constant_instruction(out, "CONSTANT", ip),
+            OpCode::ConstantLong => constant_long_instruction(out, "CONSTANT_LONG", ip),
+            OpCode::Nil => simple_instruction(out, "NIL"),
+            OpCode::True => simple_instruction(out, "TRUE"),
+            OpCode::False => simple_instruction(out, "FALSE"),
+            OpCode::Equal => simple_instruction(out, "EQUAL"),
+            OpCode::Greater => simple_instruction(out, "GREATER"),
+            OpCode::Less => simple_instruction(out, "LESS"),
+            OpCode::Negate => simple_instruction(out, "NEGATE"),
+            OpCode::Add => simple_instruction(out, "ADD"),
+            OpCode::Subtract => simple_instruction(out, "SUBTRACT"),
+            OpCode::Multiply => simple_instruction(out, "MULTIPLY"),
+            OpCode::Divide => simple_instruction(out, "DIVIDE"),
+            OpCode::Modulo => simple_instruction(out, "MODULO"),
+            OpCode::Slice => simple_instruction(out, "SLICE"),
+            OpCode::Stringify => simple_instruction(out, "STRINGIFY"),
+            OpCode::BuildTuple => byte_instruction(out, "BUILD_TUPLE", ip),
+            OpCode::Unpack => byte_instruction(out, "UNPACK", ip),
+            OpCode::Not => simple_instruction(out, "NOT"),
+            OpCode::Print => simple_instruction(out, "PRINT"),
+            OpCode::Jump => jump_instruction(out, "JUMP", ip, 1),
+            OpCode::JumpIfFalse => jump_instruction(out, "JUMP_IF_FALSE", ip, 1),
+            OpCode::PopJumpIfFalse => jump_instruction(out, "POP_JUMP_IF_FALSE", ip, 1),
+            OpCode::PopJumpIfTrue => jump_instruction(out, "POP_JUMP_IF_TRUE", ip, 1),
+            OpCode::JumpIfNotNil => jump_instruction(out, "JUMP_IF_NOT_NIL", ip, 1),
+            OpCode::Loop => jump_instruction(out, "LOOP", ip, -1),
+            OpCode::Call => byte_instruction(out, "CALL", ip),
+            OpCode::TailCall => byte_instruction(out, "TAIL_CALL", ip),
+            OpCode::Closure => closure_instruction(out, "CLOSURE", ip, false),
+            OpCode::ClosureLong => closure_instruction(out, "CLOSURE_LONG", ip, true),
+            OpCode::CloseUpvalue => simple_instruction(out, "CLOSE_UPVALUE"),
+            OpCode::Pop => simple_instruction(out, "POP"),
+            OpCode::PopN => byte_instruction(out, "POP_N", ip),
+            OpCode::Dup => simple_instruction(out, "DUP"),
+            OpCode::Swap => simple_instruction(out, "SWAP"),
+            OpCode::Rot3 => simple_instruction(out, "ROT3"),
+            OpCode::Tuck => simple_instruction(out, "TUCK"),
+            OpCode::GetLocal => byte_instruction(out, "GET_LOCAL", ip),
+            OpCode::SetLocal => byte_instruction(out, "SET_LOCAL", ip),
+            OpCode::GetLocalLong => short_instruction(out, "GET_LOCAL_LONG", ip),
+            OpCode::SetLocalLong => short_instruction(out, "SET_LOCAL_LONG", ip),
+            OpCode::GetGlobal => global_instruction(out, "GET_GLOBAL", ip),
+            OpCode::DefineGlobal => global_instruction(out, "DEFINE_GLOBAL", ip),
+            OpCode::SetGlobal => global_instruction(out, "SET_GLOBAL", ip),
+            OpCode::GetGlobalLong => global_long_instruction(out, "GET_GLOBAL_LONG", ip),
+            OpCode::DefineGlobalLong => global_long_instruction(out, "DEFINE_GLOBAL_LONG", ip),
+            OpCode::SetGlobalLong => global_long_instruction(out, "SET_GLOBAL_LONG", ip),
+            OpCode::GetUpvalue => byte_instruction(out, "GET_UPVALUE", ip),
+            OpCode::SetUpvalue => byte_instruction(out, "SET_UPVALUE", ip),
+            OpCode::Return => simple_instruction(out, "RETURN"),
         },
-        Err(_) => {
-            println!("Unknown opcode {}", byte);
-        }
+        Err(_) => writeln!(out, "Unknown opcode {}", byte),
     }
 }
 
-fn simple_instruction(name: &str) {
-    println!("{}", name);
+fn simple_instruction(out: &mut impl Write, name: &str) -> fmt::Result {
+    writeln!(out, "{}", name)
 }
 
-fn byte_instruction(name: &str, ip: &mut TracingIP) {
+fn byte_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
     let byte = ip.read();
-    println!("{:<16} {:<4}", name, byte);
+    writeln!(out, "{:<16} {:<4}", name, byte)
+}
+
+fn short_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
+    let short = ip.read_short();
+    writeln!(out, "{:<16} {:<4}", name, short)
 }
 
-fn jump_instruction(name: &str, ip: &mut TracingIP, sign: isize) {
+fn jump_instruction(
+    out: &mut impl Write,
+    name: &str,
+    ip: &mut TracingIP,
+    sign: isize,
+) -> fmt::Result {
     let offset = ip.read_short() as isize;
-    println!(
+    writeln!(
+        out,
         "{:<16} {:<4} -> {:<4}",
         name,
         offset,
         ip.offset as isize + offset * sign
-    );
+    )
+}
+
+fn constant_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
+    let constant_index = ip.read();
+    write!(out, "{:<16} {:<4} ", name, constant_index)?;
+    writeln!(out, "{}", ip.chunk.constants[constant_index as usize])
+}
+
+fn constant_long_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
+    let high = ip.read() as usize;
+    let constant_index = (high << 16) | ip.read_short() as usize;
+    write!(out, "{:<16} {:<4} ", name, constant_index)?;
+    writeln!(out, "{}", ip.chunk.constants[constant_index])
+}
+
+// The function's constant is followed by a byte and a short for each of its
+// upvalues, saying where to capture it from
+fn closure_instruction(
+    out: &mut impl Write,
+    name: &str,
+    ip: &mut TracingIP,
+    long: bool,
+) -> fmt::Result {
+    let constant_index = if long {
+        let high = ip.read() as usize;
+        (high << 16) | ip.read_short() as usize
+    } else {
+        ip.read() as usize
+    };
+    let constant = &ip.chunk.constants[constant_index];
+    writeln!(out, "{:<16} {:<4} {}", name, constant_index, constant)?;
+    match constant {
+        Value::FunctionProto(f) => {
+            for _ in 0..(f.upgrade().unwrap().content.upvalue_count) {
+                write!(out, "    | {:04} ", ip.offset)?;
+                let is_local = ip.read();
+                let index = ip.read_short();
+                let text = match is_local {
+                    0 => "upvalue",
+                    _ => "local",
+                };
+                writeln!(out, "|                {} {}", text, index)?;
+            }
+        }
+        _ => {
+            unreachable!();
+        }
+    };
+    Ok(())
 }
 
-fn constant_instruction(name: &str, ip: &mut TracingIP) {
+fn global_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
     let constant_index = ip.read();
-    print!("{:<16} {:<4} ", name, constant_index);
-    println!("{}", ip.chunk.constants[constant_index as usize]);
+    let slot = ip.read_short();
+    write!(out, "{:<16} {:<4} ", name, constant_index)?;
+    writeln!(
+        out,
+        "{} (slot {})",
+        ip.chunk.constants[constant_index as usize], slot
+    )
+}
+
+fn global_long_instruction(out: &mut impl Write, name: &str, ip: &mut TracingIP) -> fmt::Result {
+    let constant_index = ip.read_short();
+    let slot = ip.read_short();
+    write!(out, "{:<16} {:<4} ", name, constant_index)?;
+    writeln!(
+        out,
+        "{} (slot {})",
+        ip.chunk.constants[constant_index as usize], slot
+    )
 }
 
 #[allow(dead_code)]
 pub(crate) fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("== {} ==", name);
+    let mut s = String::new();
+    write_chunk(&mut s, chunk, name).unwrap();
+    print!("{}", s);
+}
+
+pub(crate) fn write_chunk(out: &mut impl Write, chunk: &Chunk, name: &str) -> fmt::Result {
+    writeln!(out, "== {} ==", name)?;
+    let mut ip = TracingIP::new(chunk, 0);
+    while ip.valid() {
+        write_instruction(out, &mut ip)?;
+    }
+    Ok(())
+}
+
+// Counts instructions rather than bytes, since operands vary in length
+pub(crate) fn count_instructions(chunk: &Chunk) -> usize {
     let mut ip = TracingIP::new(chunk, 0);
+    let mut count = 0;
+    let mut discard = String::new();
     while ip.valid() {
-        disassemble_instruction(&mut ip);
+        write_instruction(&mut discard, &mut ip).unwrap();
+        discard.clear();
+        count += 1;
     }
+    count
 }
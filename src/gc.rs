@@ -1,5 +1,5 @@
 use crate::value::{
-    Closure, Function, Native, ObjectRef, ObjectRoot, Upvalue, UpvalueLocation, Value,
+    Closure, Function, Native, ObjectRef, ObjectRoot, Tuple, Upvalue, UpvalueLocation, Value,
 };
 use crate::VM;
 
@@ -15,14 +15,128 @@ pub trait Trace: Mark + std::fmt::Display {
     fn trace(&self, wl: &mut Worklist);
 }
 
-type Worklist = Vec<Box<dyn Trace>>;
+pub type Worklist = Vec<Box<dyn Trace>>;
+
+// Identifies a value rooted with VM::root, which isn't Clone so that it can
+// only be unrooted once
+pub struct RootHandle(usize);
+
+// How many gray objects an incremental step traces before letting the
+// program run another instruction
+const GC_STEP_BUDGET: usize = 64;
+
+// Each type of object lives in its own arena, so that sweeping doesn't need
+// dynamic dispatch or a box around every object
+#[derive(Default)]
+pub struct Heap {
+    strings: Vec<ObjectRoot<String>>,
+    functions: Vec<ObjectRoot<Function>>,
+    closures: Vec<ObjectRoot<Closure>>,
+    upvalues: Vec<ObjectRoot<Upvalue>>,
+    natives: Vec<ObjectRoot<Native>>,
+    tuples: Vec<ObjectRoot<Tuple>>,
+}
+
+impl Heap {
+    pub fn len(&self) -> usize {
+        self.strings.len()
+            + self.functions.len()
+            + self.closures.len()
+            + self.upvalues.len()
+            + self.natives.len()
+            + self.tuples.len()
+    }
+
+    fn sweep(&mut self) {
+        sweep_arena(&mut self.strings);
+        sweep_arena(&mut self.functions);
+        sweep_arena(&mut self.closures);
+        sweep_arena(&mut self.upvalues);
+        sweep_arena(&mut self.natives);
+        sweep_arena(&mut self.tuples);
+    }
+
+    #[cfg(feature = "verbose_gc")]
+    fn unmarked(&self) -> Vec<String> {
+        let mut result = Vec::new();
+        result.extend(unmarked_in_arena(&self.strings));
+        result.extend(unmarked_in_arena(&self.functions));
+        result.extend(unmarked_in_arena(&self.closures));
+        result.extend(unmarked_in_arena(&self.upvalues));
+        result.extend(unmarked_in_arena(&self.natives));
+        result.extend(unmarked_in_arena(&self.tuples));
+        result
+    }
+}
+
+fn sweep_arena<T>(arena: &mut Vec<ObjectRoot<T>>) {
+    // drain_filter would be lovely here but we are using stable
+    arena.retain(|oroot| !oroot.can_free());
+    for oroot in arena {
+        oroot.unmark();
+    }
+}
+
+#[cfg(feature = "verbose_gc")]
+fn unmarked_in_arena<T: std::fmt::Display>(arena: &[ObjectRoot<T>]) -> Vec<String> {
+    arena
+        .iter()
+        .filter(|oroot| oroot.can_free())
+        .map(|oroot| oroot.to_string())
+        .collect()
+}
+
+// Implemented by every type of object the VM can manage, to find its arena
+pub trait Managed: Sized + 'static
+where
+    ObjectRoot<Self>: Trace,
+{
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>>;
+}
+
+impl Managed for String {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.strings
+    }
+}
+
+impl Managed for Function {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.functions
+    }
+}
+
+impl Managed for Closure {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.closures
+    }
+}
+
+impl Managed for Upvalue {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.upvalues
+    }
+}
+
+impl Managed for Native {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.natives
+    }
+}
+
+impl Managed for Tuple {
+    fn arena(heap: &mut Heap) -> &mut Vec<ObjectRoot<Self>> {
+        &mut heap.tuples
+    }
+}
 
 impl VM {
     pub fn collect_garbage(&mut self) {
         #[cfg(feature = "verbose_gc")]
         println!("--gc begin, {} bytes allocated", get_allocated_bytes());
 
-        let mut wl = Vec::new();
+        // any incremental cycle in progress is finished off by this one
+        let mut wl = self.gray.take().unwrap_or_default();
         self.mark_roots(&mut wl);
         loop {
             match wl.pop() {
@@ -35,8 +149,8 @@ impl VM {
         #[cfg(feature = "verbose_gc")]
         {
             print!("mark and trace completed - ");
-            let to_free: Vec<_> = self.objects.iter().filter(|obj| obj.can_free()).collect();
-            if to_free.len() > 0 {
+            let to_free = self.objects.unmarked();
+            if !to_free.is_empty() {
                 println!("the following objects will be freed:");
                 for obj in to_free {
                     println!("{}", obj);
@@ -48,30 +162,163 @@ impl VM {
 
         self.strings.retain(|interned| !interned.0.can_free());
 
-        // drain_filter would be lovely here but we are using stable
-        self.objects.retain(|oroot| !oroot.can_free());
-        for obj in &self.objects {
-            obj.unmark();
-        }
+        self.objects.sweep();
 
         #[cfg(feature = "verbose_gc")]
         println!("--gc end, {} bytes allocated", get_allocated_bytes());
+
+        if self.dump_after_gc {
+            self.dump_heap();
+        }
+    }
+
+    // Incremental collection spreads the marking over many instructions, with
+    // the gray objects (marked but not yet traced) kept in self.gray between
+    // steps. The roots are only scanned at the start and again at the end,
+    // so the barriers below stop the program from hiding an unmarked object
+    // somewhere that has already been traced. Sweeping still happens all at
+    // once at the end of the cycle, but that doesn't need any tracing.
+    pub fn begin_gc_cycle(&mut self) {
+        let mut wl = Vec::new();
+        self.mark_roots(&mut wl);
+        self.gray = Some(wl);
     }
 
+    pub fn gc_step(&mut self) {
+        let wl = self.gray.as_mut().unwrap();
+        for _ in 0..GC_STEP_BUDGET {
+            match wl.pop() {
+                None => break,
+                Some(oroot) => oroot.trace(wl),
+            }
+        }
+        if wl.is_empty() {
+            // rescanning the roots picks up whatever the program has done
+            // with them since the cycle started
+            self.collect_garbage();
+            self.next_gc = crate::memory::get_allocated_bytes() * 2;
+        }
+    }
+
+    // Objects allocated during a cycle are marked straight away, and traced
+    // like any other gray object in case they refer to unmarked ones
+    pub fn shade_new<T: 'static>(&mut self, oroot: &ObjectRoot<T>)
+    where
+        ObjectRoot<T>: Trace,
+    {
+        if let Some(wl) = &mut self.gray {
+            mark_root(oroot, wl);
+        }
+    }
+
+    // Called when a value is stored into a heap object (so far only a closed
+    // upvalue can be changed) which may already have been traced
+    pub fn write_barrier(&mut self, value: &Value) {
+        if let Some(wl) = &mut self.gray {
+            mark_value(value, wl);
+        }
+    }
+
+    // A Value only holds weak references, so one that the host program keeps
+    // hold of (say from an evaluation) can be freed by the next collection.
+    // Rooting it keeps it alive, along with whatever it refers to, until the
+    // handle is passed to unroot.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn root(&mut self, value: Value) -> RootHandle {
+        match self.host_roots.iter().position(Option::is_none) {
+            Some(index) => {
+                self.host_roots[index] = Some(value);
+                RootHandle(index)
+            }
+            None => {
+                self.host_roots.push(Some(value));
+                RootHandle(self.host_roots.len() - 1)
+            }
+        }
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn unroot(&mut self, handle: RootHandle) -> Value {
+        self.host_roots[handle.0].take().unwrap()
+    }
+
+    fn dump_heap(&self) {
+        let heap = &self.objects;
+        let counts = [
+            ("closure", heap.closures.len()),
+            ("function", heap.functions.len()),
+            ("native", heap.natives.len()),
+            ("string", heap.strings.len()),
+            ("tuple", heap.tuples.len()),
+            ("upvalue", heap.upvalues.len()),
+        ];
+        print!("--gc survivors:");
+        for (kind, count) in counts.iter() {
+            if *count > 0 {
+                print!(" {}={}", kind, count);
+            }
+        }
+        println!();
+    }
+
+    // Called before every allocation when the stress_gc feature is on, to shake
+    // out objects that are in use but not reachable from the roots
+    #[cfg(feature = "stress_gc")]
+    pub fn stress_collect(&mut self) {
+        if self.running {
+            self.collect_garbage();
+        }
+    }
+
+    // The roots are the value stack, the globals (names and values), the
+    // closures of the active call frames, the open upvalues and the values
+    // the host program has rooted. Objects are
+    // only kept alive if they can be reached from one of these by way of
+    // mark_value and the Trace impls below, so a new kind of Value or object
+    // must be handled there too.
     fn mark_roots(&mut self, wl: &mut Worklist) {
-        for value in &self.stack {
+        let trace = self.trace_gc_roots;
+        if trace {
+            println!("--gc roots");
+        }
+        for (slot, value) in self.stack.iter().enumerate() {
+            if trace {
+                println!("stack slot {}: {}", slot, value);
+            }
             mark_value(value, wl);
         }
-        for (k, v) in &self.globals {
+        for (k, &slot) in &self.global_slots {
             mark_root(&k.0, wl);
-            mark_value(v, wl);
+            if let Some(v) = &self.globals[slot] {
+                if trace {
+                    println!("global \"{}\": {}", k, v);
+                }
+                mark_value(v, wl);
+            }
         }
-        for f in &self.frames {
+        for (depth, f) in self.frames.iter().enumerate() {
+            if trace {
+                println!("frame {} closure: {}", depth, f.closure);
+            }
             mark_root::<Closure>(&f.closure, wl);
         }
         for uv in &self.open_upvalues {
+            if trace {
+                match &*uv.upgrade().unwrap().content.location.borrow() {
+                    UpvalueLocation::Stack(slot) => println!("open upvalue: stack slot {}", slot),
+                    UpvalueLocation::Heap(v) => println!("open upvalue: {}", v),
+                }
+            }
             mark_ref::<Upvalue>(uv, wl);
         }
+        for (index, value) in self.host_roots.iter().enumerate() {
+            if let Some(v) = value {
+                if trace {
+                    println!("host root {}: {}", index, v);
+                }
+                mark_value(v, wl);
+            }
+        }
         // unlike clox, our GC cannot run during compilation, so we have
         // no separate mark_compiler_roots function
     }
@@ -83,6 +330,7 @@ fn mark_value(value: &Value, wl: &mut Worklist) {
         Value::FunctionProto(oref) => mark_ref(oref, wl),
         Value::Function(oref) => mark_ref(oref, wl),
         Value::Native(oref) => mark_ref(oref, wl),
+        Value::Tuple(oref) => mark_ref(oref, wl),
         Value::Bool(_) | Value::Number(_) | Value::Nil => (),
     }
 }
@@ -140,16 +388,53 @@ impl Trace for ObjectRoot<Upvalue> {
     fn trace(&self, wl: &mut Worklist) {
         match &*self.content.location.borrow() {
             UpvalueLocation::Stack(_) => (),
-            UpvalueLocation::Heap(v) => mark_value(&v, wl),
+            UpvalueLocation::Heap(v) => mark_value(v, wl),
+        }
+    }
+}
+
+impl Trace for ObjectRoot<Tuple> {
+    fn trace(&self, wl: &mut Worklist) {
+        for item in &self.content.items {
+            mark_value(item, wl);
         }
     }
 }
 
 impl<T> Mark for ObjectRoot<T> {
     fn can_free(&self) -> bool {
-        *self.marked.borrow() == false
+        !*self.marked.borrow()
     }
     fn unmark(&self) {
         *self.marked.borrow_mut() = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::VM;
+    use std::rc::Rc;
+
+    // f and h each capture the other, so only the collector can free them
+    #[test]
+    fn closures_that_refer_to_each_other_are_collected() {
+        let mut vm = VM::new();
+        vm.interpret_source(
+            "var a;\n{\n  var g;\n  fun f() { return g; }\n  fun h() { return f; }\n  g = h;\n  a = f;\n}",
+        )
+        .unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.objects.closures.len(), 2);
+        assert_eq!(vm.objects.upvalues.len(), 2);
+        assert_eq!(vm.objects.functions.len(), 2);
+        let closures: Vec<_> = vm.objects.closures.iter().map(Rc::downgrade).collect();
+
+        vm.interpret_source("a = nil;").unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.objects.closures.len(), 0);
+        assert_eq!(vm.objects.upvalues.len(), 0);
+        assert_eq!(vm.objects.functions.len(), 0);
+        // nothing else was holding on to them
+        assert!(closures.iter().all(|c| c.upgrade().is_none()));
+    }
+}
@@ -0,0 +1,103 @@
+// A textual @include preprocessor which runs before scanning. A line of the form
+// `@include "path"` is replaced with the contents of the named file (relative to
+// the file containing the directive), so the included code shares the scope of
+// the code around it. This is done line by line without any knowledge of Lox
+// syntax, so a directive inside a multi-line string is still expanded.
+
+use crate::LineNo;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub enum IncludeError {
+    Unreadable(String),
+    Cycle(Vec<String>),
+    BadDirective(String, LineNo),
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncludeError::Unreadable(path) => write!(f, "Could not read input file: {}", path),
+            IncludeError::Cycle(paths) => write!(f, "Include cycle: {}", paths.join(" -> ")),
+            IncludeError::BadDirective(path, line) => write!(
+                f,
+                "[{} line {}] Error: Expect a quoted file name after '@include'.",
+                path, line
+            ),
+        }
+    }
+}
+
+// Maps each line of the preprocessed source back to the file and line it came
+// from, so that errors can be reported against the original files
+pub struct LineMap {
+    files: Vec<String>,
+    lines: Vec<(usize, LineNo)>,
+}
+
+impl LineMap {
+    pub fn describe(&self, line: LineNo) -> String {
+        match self.lines.get(line as usize - 1) {
+            // lines from the main file are reported just as they would be without
+            // the preprocessor
+            Some(&(0, n)) => format!("line {}", n),
+            Some(&(file, n)) => format!("{} line {}", self.files[file], n),
+            None => format!("line {}", line),
+        }
+    }
+}
+
+pub fn preprocess(path: &str) -> Result<(String, LineMap), IncludeError> {
+    let mut map = LineMap {
+        files: Vec::new(),
+        lines: Vec::new(),
+    };
+    let mut output = String::new();
+    include_file(Path::new(path), &mut map, &mut output, &mut Vec::new())?;
+    Ok((output, map))
+}
+
+fn include_file(
+    path: &Path,
+    map: &mut LineMap,
+    output: &mut String,
+    stack: &mut Vec<(PathBuf, String)>,
+) -> Result<(), IncludeError> {
+    let name = path.display().to_string();
+    let canonical = path
+        .canonicalize()
+        .map_err(|_| IncludeError::Unreadable(name.clone()))?;
+    if let Some(start) = stack.iter().position(|(p, _)| *p == canonical) {
+        let mut cycle: Vec<String> = stack[start..].iter().map(|(_, n)| n.clone()).collect();
+        cycle.push(name);
+        return Err(IncludeError::Cycle(cycle));
+    }
+    let source =
+        std::fs::read_to_string(path).map_err(|_| IncludeError::Unreadable(name.clone()))?;
+    let file = map.files.len();
+    map.files.push(name.clone());
+    stack.push((canonical, name.clone()));
+    for (i, line) in source.lines().enumerate() {
+        let line_no = (i + 1) as LineNo;
+        match line.trim_start().strip_prefix("@include") {
+            None => {
+                output.push_str(line);
+                output.push('\n');
+                map.lines.push((file, line_no));
+            }
+            Some(rest) => {
+                let rest = rest.trim();
+                if rest.len() < 2 || !rest.starts_with('"') || !rest.ends_with('"') {
+                    return Err(IncludeError::BadDirective(name, line_no));
+                }
+                let target = path
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(&rest[1..rest.len() - 1]);
+                include_file(&target, map, output, stack)?;
+            }
+        }
+    }
+    stack.pop();
+    Ok(())
+}
@@ -0,0 +1,489 @@
+// A file format for compiled scripts, so that they can be run without going
+// through the scanner and compiler again. A file is a magic number and a
+// version byte followed by the script's function, whose constants include the
+// functions declared inside it. Numbers are little-endian, and lengths, offsets
+// and line numbers are all written as u32.
+//
+// Each chunk goes through Chunk::verify when it's read back, so a file that
+// has been edited by hand can't make the VM read past the end of the code,
+// use a constant that isn't there or jump into the middle of an instruction.
+// Code that misuses the stack is only caught when it runs.
+
+use crate::value::{create_string, manage, Function, Value};
+use crate::{dis, Chunk, LineNo, OpCode, TracingIP, VM};
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+// Bump this whenever the opcodes or the layout below change
+const VERSION: u8 = 12;
+
+const TAG_NUMBER: u8 = 0;
+const TAG_STRING: u8 = 1;
+const TAG_FUNCTION: u8 = 2;
+
+pub enum LoxcError {
+    Io(io::Error),
+    NotBytecode,
+    WrongVersion(u8),
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for LoxcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxcError::Io(e) => write!(f, "Could not read bytecode: {}", e),
+            LoxcError::NotBytecode => write!(f, "Not a compiled Lox file."),
+            LoxcError::WrongVersion(v) => write!(
+                f,
+                "Compiled for bytecode version {} but this is version {}.",
+                v, VERSION
+            ),
+            LoxcError::Corrupt(what) => write!(f, "Corrupt bytecode: {}.", what),
+        }
+    }
+}
+
+impl From<io::Error> for LoxcError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => LoxcError::Corrupt("unexpected end of file"),
+            _ => LoxcError::Io(e),
+        }
+    }
+}
+
+pub fn write_program(function: &Function, out: &mut impl Write) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    serialize_function(function, out)
+}
+
+pub fn read_program(vm: &mut VM, input: &mut impl Read) -> Result<Function, LoxcError> {
+    let mut magic = [0; 4];
+    if input.read_exact(&mut magic).is_err() || &magic != MAGIC {
+        return Err(LoxcError::NotBytecode);
+    }
+    let version = read_u8(input)?;
+    if version != VERSION {
+        return Err(LoxcError::WrongVersion(version));
+    }
+    deserialize_function(vm, input)
+}
+
+impl Chunk {
+    pub fn serialize(&self, out: &mut impl Write) -> io::Result<()> {
+        write_u32(out, self.code.len())?;
+        out.write_all(&self.code)?;
+        write_u32(out, self.lines.len())?;
+        for &(offset, line) in &self.lines {
+            write_u32(out, offset)?;
+            write_u32(out, line as usize)?;
+        }
+        write_u32(out, self.constants.len())?;
+        for constant in &self.constants {
+            serialize_constant(constant, out)?;
+        }
+        Ok(())
+    }
+
+    pub fn deserialize(vm: &mut VM, input: &mut impl Read) -> Result<Self, LoxcError> {
+        let mut chunk = Chunk::new();
+        let code_len = read_u32(input)?;
+        chunk.code = read_bytes(input, code_len)?;
+        for _ in 0..read_u32(input)? {
+            let offset = read_u32(input)?;
+            let line = read_u32(input)? as LineNo;
+            chunk.lines.push((offset, line));
+        }
+        for _ in 0..read_u32(input)? {
+            let constant = deserialize_constant(vm, input)?;
+            chunk.push_constant(constant);
+        }
+        Ok(chunk)
+    }
+
+    // Checks that running the code can't take the VM past the end of it, to a
+    // constant that isn't there, or into the middle of an instruction. Besides
+    // the jumps, execution can also start at each of the entry points, which
+    // are where a function's default parameters are filled in.
+    pub fn verify(&self, entry_points: &[usize]) -> Result<(), &'static str> {
+        let code = &self.code;
+        let operand = |offset: usize| code.get(offset).copied().ok_or("code ends mid-instruction");
+        let short = |offset: usize| -> Result<usize, &'static str> {
+            Ok((operand(offset)? as usize) << 8 | operand(offset + 1)? as usize)
+        };
+        let long = |offset: usize| -> Result<usize, &'static str> {
+            Ok((operand(offset)? as usize) << 16 | short(offset + 1)?)
+        };
+        let constant = |index: usize| self.constants.get(index).ok_or("no such constant");
+        let mut starts = vec![false; code.len()];
+        let mut targets = entry_points.to_vec();
+        let mut last = None;
+        let mut offset = 0;
+        while offset < code.len() {
+            starts[offset] = true;
+            let instruction = OpCode::try_from(code[offset]).map_err(|_| "unknown opcode")?;
+            let length = match instruction {
+                OpCode::Constant => {
+                    constant(operand(offset + 1)? as usize)?;
+                    2
+                }
+                OpCode::ConstantLong => {
+                    constant(long(offset + 1)?)?;
+                    4
+                }
+                OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::PopJumpIfFalse
+                | OpCode::PopJumpIfTrue
+                | OpCode::JumpIfNotNil => {
+                    targets.push(offset + 3 + short(offset + 1)?);
+                    3
+                }
+                OpCode::Loop => {
+                    let target = (offset + 3).checked_sub(short(offset + 1)?);
+                    targets.push(target.ok_or("jump before the start of the code")?);
+                    3
+                }
+                OpCode::Call
+                | OpCode::TailCall
+                | OpCode::PopN
+                | OpCode::BuildTuple
+                | OpCode::Unpack
+                | OpCode::GetLocal
+                | OpCode::SetLocal
+                | OpCode::GetUpvalue
+                | OpCode::SetUpvalue => {
+                    operand(offset + 1)?;
+                    2
+                }
+                OpCode::GetLocalLong | OpCode::SetLocalLong => {
+                    short(offset + 1)?;
+                    3
+                }
+                OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal => {
+                    match constant(operand(offset + 1)? as usize)? {
+                        Value::String(_) => (),
+                        _ => return Err("global name isn't a string"),
+                    }
+                    // the slot is replaced when the chunk is loaded
+                    short(offset + 2)?;
+                    4
+                }
+                OpCode::GetGlobalLong | OpCode::DefineGlobalLong | OpCode::SetGlobalLong => {
+                    match constant(short(offset + 1)?)? {
+                        Value::String(_) => (),
+                        _ => return Err("global name isn't a string"),
+                    }
+                    short(offset + 3)?;
+                    5
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let (index, width) = match instruction {
+                        OpCode::Closure => (operand(offset + 1)? as usize, 1),
+                        _ => (long(offset + 1)?, 3),
+                    };
+                    let upvalue_count = match constant(index)? {
+                        Value::FunctionProto(f) => f.upgrade().unwrap().content.upvalue_count,
+                        _ => return Err("closure of something that isn't a function"),
+                    };
+                    operand(offset + width + 3 * upvalue_count)?;
+                    1 + width + 3 * upvalue_count
+                }
+                _ => 1,
+            };
+            last = Some(instruction);
+            offset += length;
+        }
+        // the VM relies on this to avoid checking for the end of the chunk
+        if !matches!(last, Some(OpCode::Return)) {
+            return Err("code doesn't end with a return");
+        }
+        if targets
+            .iter()
+            .any(|&t| !starts.get(t).copied().unwrap_or(false))
+        {
+            return Err("jump to somewhere that isn't an instruction");
+        }
+        Ok(())
+    }
+}
+
+// The slots in the file are the ones the compiling VM gave out, which mean
+// nothing to this one, so each global instruction gets the slot this VM has
+// for its name instead
+fn assign_global_slots(vm: &mut VM, chunk: &mut Chunk) -> Result<(), LoxcError> {
+    let mut operands = Vec::new();
+    let mut ip = TracingIP::new(chunk, 0);
+    let mut discard = String::new();
+    while ip.valid() {
+        match OpCode::try_from(chunk.code[ip.offset]) {
+            Ok(OpCode::GetGlobal) | Ok(OpCode::SetGlobal) | Ok(OpCode::DefineGlobal) => {
+                operands.push((ip.offset + 1, false))
+            }
+            Ok(OpCode::GetGlobalLong)
+            | Ok(OpCode::SetGlobalLong)
+            | Ok(OpCode::DefineGlobalLong) => operands.push((ip.offset + 1, true)),
+            _ => (),
+        }
+        dis::write_instruction(&mut discard, &mut ip).unwrap();
+        discard.clear();
+    }
+    for (offset, long) in operands {
+        // verify has made sure the name is a string
+        let (name, slot_offset) = if long {
+            let index = (chunk.code[offset] as usize) << 8 | chunk.code[offset + 1] as usize;
+            (index, offset + 2)
+        } else {
+            (chunk.code[offset] as usize, offset + 1)
+        };
+        let name = chunk.constants[name].clone();
+        let slot = vm.global_slot(name.try_into().unwrap());
+        let slot = u16::try_from(slot).map_err(|_| LoxcError::Corrupt("too many globals"))?;
+        chunk.code[slot_offset..slot_offset + 2].copy_from_slice(&slot.to_be_bytes());
+    }
+    Ok(())
+}
+
+fn serialize_function(function: &Function, out: &mut impl Write) -> io::Result<()> {
+    match &function.name {
+        None if function.anonymous => out.write_all(&[2])?,
+        None => out.write_all(&[0])?,
+        Some(oref) => {
+            out.write_all(&[1])?;
+            write_string(out, &oref.upgrade().unwrap().content)?;
+        }
+    }
+    write_u32(out, function.arity)?;
+    write_u32(out, function.upvalue_count)?;
+    write_u32(out, function.defaults.len())?;
+    for &offset in &function.defaults {
+        write_u32(out, offset)?;
+    }
+    function.chunk.serialize(out)
+}
+
+fn deserialize_function(vm: &mut VM, input: &mut impl Read) -> Result<Function, LoxcError> {
+    let (name, anonymous) = match read_u8(input)? {
+        0 => (None, false),
+        1 => (Some(read_string(input)?), false),
+        2 => (None, true),
+        _ => return Err(LoxcError::Corrupt("bad function name")),
+    };
+    let arity = read_u32(input)?;
+    let mut function = Function::new_in_vm(vm, name.as_deref(), arity);
+    function.anonymous = anonymous;
+    function.upvalue_count = read_u32(input)?;
+    for _ in 0..read_u32(input)? {
+        let offset = read_u32(input)?;
+        function.defaults.push(offset);
+    }
+    // there is one entry point for each number of optional arguments passed,
+    // from none of them up to all of them, so at most one more than the arity
+    if function.defaults.len() > function.arity + 1 {
+        return Err(LoxcError::Corrupt("more defaults than parameters"));
+    }
+    function.chunk = Chunk::deserialize(vm, input)?;
+    function
+        .chunk
+        .verify(&function.defaults)
+        .map_err(LoxcError::Corrupt)?;
+    assign_global_slots(vm, &mut function.chunk)?;
+    Ok(function)
+}
+
+// Only numbers, strings and functions ever end up in the constant pool
+fn serialize_constant(constant: &Value, out: &mut impl Write) -> io::Result<()> {
+    match constant {
+        Value::Number(n) => {
+            out.write_all(&[TAG_NUMBER])?;
+            out.write_all(&n.to_le_bytes())
+        }
+        Value::String(oref) => {
+            out.write_all(&[TAG_STRING])?;
+            write_string(out, &oref.upgrade().unwrap().content)
+        }
+        Value::FunctionProto(oref) => {
+            out.write_all(&[TAG_FUNCTION])?;
+            serialize_function(&oref.upgrade().unwrap().content, out)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("can't write a {} constant", constant.type_name()),
+        )),
+    }
+}
+
+fn deserialize_constant(vm: &mut VM, input: &mut impl Read) -> Result<Value, LoxcError> {
+    match read_u8(input)? {
+        TAG_NUMBER => {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(Value::Number(f64::from_le_bytes(bytes)))
+        }
+        TAG_STRING => {
+            let s = read_string(input)?;
+            Ok(create_string(vm, &s).into())
+        }
+        TAG_FUNCTION => {
+            let function = deserialize_function(vm, input)?;
+            Ok(Value::FunctionProto(manage(vm, function)))
+        }
+        _ => Err(LoxcError::Corrupt("unknown constant type")),
+    }
+}
+
+fn write_u32(out: &mut impl Write, n: usize) -> io::Result<()> {
+    let n = u32::try_from(n).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "value too large for a bytecode file",
+        )
+    })?;
+    out.write_all(&n.to_le_bytes())
+}
+
+fn write_string(out: &mut impl Write, s: &str) -> io::Result<()> {
+    write_u32(out, s.len())?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_u8(input: &mut impl Read) -> Result<u8, LoxcError> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32(input: &mut impl Read) -> Result<usize, LoxcError> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes) as usize)
+}
+
+fn read_bytes(input: &mut impl Read, len: usize) -> Result<Vec<u8>, LoxcError> {
+    // read through take() so that a corrupt length can't allocate gigabytes
+    let mut bytes = Vec::new();
+    input.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(LoxcError::Corrupt("unexpected end of file"));
+    }
+    Ok(bytes)
+}
+
+fn read_string(input: &mut impl Read) -> Result<String, LoxcError> {
+    let len = read_u32(input)?;
+    String::from_utf8(read_bytes(input, len)?).map_err(|_| LoxcError::Corrupt("invalid string"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(code: Vec<OpCode>) -> Chunk {
+        let mut chunk = Chunk::new();
+        for op in code {
+            chunk.write(op.into(), 1);
+        }
+        chunk
+    }
+
+    fn read(bytes: &[u8]) -> Result<Function, &'static str> {
+        let mut vm = VM::new();
+        match read_program(&mut vm, &mut &bytes[..]) {
+            Ok(function) => Ok(function),
+            Err(LoxcError::Corrupt(what)) => Err(what),
+            Err(e) => panic!("expected corrupt bytecode, got {}", e),
+        }
+    }
+
+    #[test]
+    fn constant_must_exist() {
+        let mut c = chunk(vec![OpCode::Constant]);
+        c.write(3, 1);
+        c.write(OpCode::Return.into(), 1);
+        assert_eq!(c.verify(&[]), Err("no such constant"));
+    }
+
+    #[test]
+    fn jump_must_land_on_an_instruction() {
+        let mut c = chunk(vec![OpCode::Jump]);
+        c.write(0, 1);
+        c.write(10, 1);
+        c.write(OpCode::Return.into(), 1);
+        assert_eq!(
+            c.verify(&[]),
+            Err("jump to somewhere that isn't an instruction")
+        );
+        let mut c = chunk(vec![OpCode::Loop]);
+        c.write(0, 1);
+        c.write(10, 1);
+        c.write(OpCode::Return.into(), 1);
+        assert_eq!(c.verify(&[]), Err("jump before the start of the code"));
+    }
+
+    #[test]
+    fn code_must_end_with_a_return() {
+        let c = chunk(vec![OpCode::Nil, OpCode::Print]);
+        assert_eq!(c.verify(&[]), Err("code doesn't end with a return"));
+        let c = chunk(vec![OpCode::Nil, OpCode::Return]);
+        assert_eq!(c.verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn operands_must_be_complete() {
+        let c = chunk(vec![OpCode::Return, OpCode::GetLocalLong]);
+        assert_eq!(c.verify(&[]), Err("code ends mid-instruction"));
+    }
+
+    #[test]
+    fn defaults_must_fit_the_arity() {
+        let mut vm = VM::new();
+        let mut function = Function::new_in_vm(&mut vm, Some("f"), 1);
+        function.chunk = chunk(vec![OpCode::Nil, OpCode::Return]);
+        function.defaults = vec![0, 0];
+        let mut bytes = Vec::new();
+        write_program(&function, &mut bytes).unwrap();
+        assert!(read(&bytes).is_ok());
+
+        function.defaults = vec![0, 0, 0];
+        let mut bytes = Vec::new();
+        write_program(&function, &mut bytes).unwrap();
+        assert_eq!(read(&bytes).err(), Some("more defaults than parameters"));
+    }
+
+    #[test]
+    fn truncated_file_is_corrupt() {
+        let mut vm = VM::new();
+        let mut function = Function::new_in_vm(&mut vm, None, 0);
+        function.chunk = chunk(vec![OpCode::Nil, OpCode::Return]);
+        let mut bytes = Vec::new();
+        write_program(&function, &mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(read(&bytes).err(), Some("unexpected end of file"));
+    }
+
+    #[test]
+    fn program_reads_back_the_same() {
+        let mut vm = VM::new();
+        let source = "var s = \"text\";\n\
+                      fun f(a, b = 2.5) {\n\
+                        fun g() { return a + b; }\n\
+                        return g;\n\
+                      }\n\
+                      print f(1)() + -0.0;";
+        let function = crate::compiler::compile(source, &mut vm).unwrap();
+        let mut bytes = Vec::new();
+        write_program(&function, &mut bytes).unwrap();
+        let copy = match read_program(&mut vm, &mut &bytes[..]) {
+            Ok(function) => function,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!(vm.disassemble(&copy), vm.disassemble(&function));
+        assert_eq!(copy.chunk.lines, function.chunk.lines);
+        let mut again = Vec::new();
+        write_program(&copy, &mut again).unwrap();
+        assert_eq!(again, bytes);
+    }
+}
@@ -1,20 +1,29 @@
-use gc::Trace;
+use gc::Heap;
+use include::LineMap;
 use memory::get_allocated_bytes;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use scanner::{tokenize, TokenType};
 use std::collections::{HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, Read, Write};
 use std::iter::Peekable;
+use std::rc::Rc;
 use std::slice::Iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use value::{
     create_string, manage, Closure, Function, InternedString, Native, NativeFn, ObjectRef,
-    ObjectRoot, Upvalue, UpvalueLocation, Value,
+    ObjectRoot, Tuple, Upvalue, UpvalueLocation, Value,
 };
 
+mod ansi;
+mod bench;
 mod compiler;
 mod dis;
 mod gc;
+mod include;
+mod loxc;
 mod memory;
 mod parser;
 mod scanner;
@@ -24,6 +33,7 @@ mod value;
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
@@ -35,20 +45,40 @@ pub enum OpCode {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Slice,
+    Stringify,
+    BuildTuple,
+    Unpack,
     Not,
     Print,
     Jump,
     JumpIfFalse,
+    PopJumpIfFalse,
+    PopJumpIfTrue,
+    JumpIfNotNil,
     Loop,
     Call,
+    TailCall,
     Closure,
+    ClosureLong,
     CloseUpvalue,
     Pop,
+    PopN,
+    Dup,
+    Swap,
+    Rot3,
+    Tuck,
     GetLocal,
     SetLocal,
+    GetLocalLong,
+    SetLocalLong,
     GetGlobal,
     DefineGlobal,
     SetGlobal,
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
     GetUpvalue,
     SetUpvalue,
     Return,
@@ -56,10 +86,37 @@ pub enum OpCode {
 
 type LineNo = u32;
 
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * 256;
+
 pub struct Chunk {
     code: Vec<u8>,
     constants: Vec<Value>,
     lines: Vec<(usize, LineNo)>,
+    // where each number, string or function is in the pool, so the compiler
+    // can reuse constants without searching the whole pool for them
+    constant_indices: HashMap<ConstantKey, usize>,
+}
+
+// Numbers are compared by their bits so that 0 and -0 stay distinct and NaN is
+// reused. Strings are interned and functions are only ever equal to
+// themselves, so both are compared by address.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(usize),
+    Function(usize),
+}
+
+impl ConstantKey {
+    fn new(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::String(oref) => Some(ConstantKey::String(oref.as_ptr() as usize)),
+            Value::FunctionProto(oref) => Some(ConstantKey::Function(oref.as_ptr() as usize)),
+            _ => None,
+        }
+    }
 }
 
 impl Chunk {
@@ -68,6 +125,7 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             lines: Vec::new(),
+            constant_indices: HashMap::new(),
         }
     }
 
@@ -79,12 +137,55 @@ impl Chunk {
         }
     }
 
-    fn add_constant(&mut self, value: Value) -> Result<u8, CompileError> {
-        if self.constants.len() > (u8::MAX as usize) {
-            return Err(CompileError::TooManyConstants);
+    // Reuse an existing constant where we can, which stops repeated literals and
+    // global names from using up the pool
+    fn find_constant(&self, value: &Value) -> Option<usize> {
+        let key = ConstantKey::new(value)?;
+        self.constant_indices.get(&key).copied()
+    }
+
+    fn push_constant(&mut self, value: Value) -> usize {
+        let index = self.constants.len();
+        if let Some(key) = ConstantKey::new(&value) {
+            self.constant_indices.entry(key).or_insert(index);
         }
         self.constants.push(value);
-        Ok((self.constants.len() - 1) as u8)
+        index
+    }
+
+    // Forget the constants from len on, once nothing refers to them
+    fn truncate_constants(&mut self, len: usize) {
+        for value in self.constants.drain(len..) {
+            if let Some(key) = ConstantKey::new(&value) {
+                self.constant_indices.remove(&key);
+            }
+        }
+    }
+
+    // Cut a range out of the code, moving the line numbers after it to match.
+    // Any jumps across the range have to be fixed up by the caller.
+    fn remove_code(&mut self, start: usize, end: usize) {
+        self.code.drain(start..end);
+        let removed = end - start;
+        self.lines
+            .retain(|&(offset, _)| offset < start || offset >= end);
+        for (offset, _) in self.lines.iter_mut() {
+            if *offset >= end {
+                *offset -= removed;
+            }
+        }
+    }
+
+    // Every instruction that takes a constant has a long form with a 24-bit
+    // operand for when the index doesn't fit in a byte
+    fn add_long_constant(&mut self, value: Value) -> Result<usize, CompileError> {
+        if let Some(index) = self.find_constant(&value) {
+            return Ok(index);
+        }
+        if self.constants.len() >= 1 << 24 {
+            return Err(CompileError::TooManyConstants);
+        }
+        Ok(self.push_constant(value))
     }
 }
 
@@ -116,7 +217,11 @@ impl<'a> TracingIP<'a> {
         let old_line = self.line;
         loop {
             match self.new_lines.peek() {
-                Some(&&(offs, _)) if offs < self.offset => self.new_lines.next(),
+                Some(&&(offs, l)) if offs < self.offset => {
+                    // only when starting partway through a run, or after a jump
+                    self.line = Some(l);
+                    self.new_lines.next()
+                }
                 Some(&&(offs, l)) if offs == self.offset => {
                     self.line = Some(l);
                     self.new_lines.next();
@@ -150,6 +255,12 @@ impl<'a> TracingIP<'a> {
         self.chunk.constants[index as usize].clone()
     }
 
+    fn read_constant_long(&mut self) -> Value {
+        let high = self.read() as usize;
+        let index = (high << 16) | self.read_short() as usize;
+        self.chunk.constants[index].clone()
+    }
+
     fn get_line(&self) -> Option<LineNo> {
         self.line
     }
@@ -192,17 +303,22 @@ impl<'a> IP<'a> {
         self.chunk.constants[index as usize].clone()
     }
 
-    // This is much more expensive than with TracingIP because this is the
-    // uncommon case we didn't optimise for
+    fn read_constant_long(&mut self) -> Value {
+        let high = self.read() as usize;
+        let index = (high << 16) | self.read_short() as usize;
+        self.chunk.constants[index].clone()
+    }
+
+    // This is more expensive than with TracingIP because this is the uncommon
+    // case we didn't optimise for, but the runs of lines are in order of
+    // offset, so at least we can binary search for the last one that starts
+    // at or before the current offset
     fn get_line(&self) -> Option<LineNo> {
-        let mut line: Option<LineNo> = None;
-        for &(offs, n) in self.chunk.lines.iter() {
-            if offs > self.offset {
-                break;
-            }
-            line = Some(n)
-        }
-        line
+        let runs = self
+            .chunk
+            .lines
+            .partition_point(|&(offs, _)| offs <= self.offset);
+        runs.checked_sub(1).map(|i| self.chunk.lines[i].1)
     }
 }
 
@@ -216,6 +332,7 @@ pub struct CallFrame {
 pub enum CompileError {
     ParseError,
     TooManyConstants,
+    TooManyGlobals,
     TooManyLocals,
     DuplicateName,
     UninitializedLocal,
@@ -223,8 +340,16 @@ pub enum CompileError {
     TooFarToLoop,
     TooManyParameters,
     TooManyArguments,
+    TooManyTupleItems,
     TooManyUpvalues,
     ReturnAtTopLevel,
+    AssignmentMismatch(usize, usize),
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    UnknownEscape(char),
+    RequiredAfterDefault,
+    AssignToConst,
+    ChainedComparison,
 }
 
 #[derive(Debug, Clone)]
@@ -235,9 +360,24 @@ pub enum RuntimeError {
     StackOverflow,
     TypeError(&'static str, String, bool),
     InvalidAddition(String, String),
+    InvalidComparison(String, String),
     UndefinedVariable(String),
     NotCallable,
     WrongArity(usize, usize),
+    UnpackMismatch(usize, usize),
+    NotIndexable(&'static str),
+    InvalidSliceIndex(String),
+    SliceOutOfRange(usize, usize),
+    ReversedSlice(usize, usize),
+    IndexOutOfBounds(String, usize),
+    OutputLimitExceeded(usize),
+    InstructionLimitExceeded(u64),
+    Interrupted,
+    AssertionFailed(String),
+    InvalidExitCode(String),
+    Exit(u8),
+    DivisionByZero,
+    CorruptBytecode,
 }
 
 #[derive(Debug, Clone)]
@@ -251,6 +391,7 @@ impl fmt::Display for CompileError {
         match self {
             CompileError::ParseError => write!(f, "Parse error."),
             CompileError::TooManyConstants => write!(f, "Too many constants in one chunk."),
+            CompileError::TooManyGlobals => write!(f, "Too many global variables."),
             CompileError::TooManyLocals => write!(f, "Too many local variables in function."),
             CompileError::DuplicateName => {
                 write!(f, "Already a variable with this name in this scope.")
@@ -262,48 +403,123 @@ impl fmt::Display for CompileError {
             CompileError::TooFarToLoop => write!(f, "Loop body too large."),
             CompileError::TooManyParameters => write!(f, "Can't have more than 255 parameters."),
             CompileError::TooManyArguments => write!(f, "Can't have more than 255 arguments."),
+            CompileError::TooManyTupleItems => {
+                write!(f, "Can't have more than 255 items in a tuple.")
+            }
             CompileError::TooManyUpvalues => write!(f, "Too many closure variables in function."),
             CompileError::ReturnAtTopLevel => write!(f, "Can't return from top-level code."),
+            CompileError::AssignmentMismatch(targets, values) => write!(
+                f,
+                "Expected {} values to assign but got {}.",
+                targets, values
+            ),
+            CompileError::BreakOutsideLoop => write!(f, "Can't use 'break' outside of a loop."),
+            CompileError::ContinueOutsideLoop => {
+                write!(f, "Can't use 'continue' outside of a loop.")
+            }
+            CompileError::UnknownEscape(c) => write!(f, "Unknown escape sequence '\\{}'.", c),
+            CompileError::RequiredAfterDefault => write!(
+                f,
+                "Parameters without a default can't follow ones with a default."
+            ),
+            CompileError::AssignToConst => write!(f, "Can't assign to a constant."),
+            CompileError::ChainedComparison => {
+                write!(f, "Can't chain comparisons; use 'and' to combine them.")
+            }
+        }
+    }
+}
+
+impl RuntimeError {
+    // clox words some of its errors differently, which we can match either at
+    // compile time with the lox_errors feature or at runtime with --compat-clox
+    fn clox_message(&self) -> Option<String> {
+        match self {
+            RuntimeError::TypeError(t, _, true) => Some(format!("Operands must be {}s.", t)),
+            RuntimeError::TypeError(t, _, false) => Some(format!("Operand must be a {}.", t)),
+            RuntimeError::InvalidAddition(_, _) => {
+                Some("Operands must be two numbers or two strings.".to_owned())
+            }
+            RuntimeError::InvalidComparison(_, _) => Some("Operands must be numbers.".to_owned()),
+            _ => None,
         }
     }
 }
 
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if cfg!(feature = "lox_errors") {
+            if let Some(message) = self.clox_message() {
+                return write!(f, "{}", message);
+            }
+        }
         match self {
             RuntimeError::UnknownOpcode => write!(f, "Unknown opcode."),
             RuntimeError::EndOfChunk => write!(f, "Unexpected end of chunk."),
+            RuntimeError::CorruptBytecode => write!(f, "Corrupt bytecode."),
             RuntimeError::StackUnderflow => write!(f, "Stack underflow."),
             RuntimeError::StackOverflow => write!(f, "Stack overflow."),
             RuntimeError::TypeError(t, v, _plural) => {
-                #[cfg(not(feature = "lox_errors"))]
-                {
-                    return write!(f, "Expected a {} value but found: {}.", t, v);
-                }
-                #[cfg(feature = "lox_errors")]
-                {
-                    if *plural {
-                        return write!(f, "Operands must be {}s.", t);
-                    } else {
-                        return write!(f, "Operand must be a {}.", t);
-                    }
-                }
+                write!(f, "Expected a {} value but found: {}.", t, v)
             }
             RuntimeError::InvalidAddition(v1, v2) => {
-                #[cfg(not(feature = "lox_errors"))]
-                {
-                    return write!(f, "Invalid types for + operator: {}, {}.", v1, v2);
-                }
-                #[cfg(feature = "lox_errors")]
-                {
-                    return write!(f, "Operands must be two numbers or two strings.");
-                }
+                write!(f, "Invalid types for + operator: {}, {}.", v1, v2)
+            }
+            RuntimeError::InvalidComparison(v1, v2) => {
+                write!(
+                    f,
+                    "Can't compare a string with a non-string: {}, {}.",
+                    v1, v2
+                )
             }
             RuntimeError::UndefinedVariable(name) => write!(f, "Undefined variable '{}'.", name),
             RuntimeError::NotCallable => write!(f, "Can only call functions and classes."),
             RuntimeError::WrongArity(expect, actual) => {
                 write!(f, "Expected {} arguments but got {}.", expect, actual)
             }
+            RuntimeError::UnpackMismatch(expect, actual) => {
+                write!(
+                    f,
+                    "Expected {} values to unpack but got {}.",
+                    expect, actual
+                )
+            }
+            RuntimeError::NotIndexable(t) => write!(f, "Can't index a value of type {}.", t),
+            RuntimeError::InvalidSliceIndex(v) => {
+                write!(
+                    f,
+                    "Slice index must be a non-negative integer but found: {}.",
+                    v
+                )
+            }
+            RuntimeError::SliceOutOfRange(index, len) => write!(
+                f,
+                "Slice index {} is out of range for a string of length {}.",
+                index, len
+            ),
+            RuntimeError::ReversedSlice(start, end) => {
+                write!(f, "Slice start {} is after slice end {}.", start, end)
+            }
+            RuntimeError::IndexOutOfBounds(index, len) => write!(
+                f,
+                "Index {} is out of bounds for a string of length {}.",
+                index, len
+            ),
+            RuntimeError::OutputLimitExceeded(limit) => {
+                write!(f, "Output limit of {} bytes exceeded.", limit)
+            }
+            RuntimeError::InstructionLimitExceeded(limit) => {
+                write!(f, "Instruction limit of {} exceeded.", limit)
+            }
+            RuntimeError::AssertionFailed(message) => write!(f, "Assertion failed: {}.", message),
+            RuntimeError::InvalidExitCode(v) => write!(
+                f,
+                "Exit code must be an integer from 0 to 255 but found: {}.",
+                v
+            ),
+            RuntimeError::Exit(code) => write!(f, "Exited with code {}.", code),
+            RuntimeError::DivisionByZero => write!(f, "Division by zero."),
+            RuntimeError::Interrupted => write!(f, "Interrupted."),
         }
     }
 }
@@ -314,67 +530,324 @@ type InterpretResult = Result<(), VMError>;
 
 pub struct VM {
     stack: Vec<Value>,
-    objects: Vec<Box<dyn Trace>>,
+    objects: Heap,
     strings: HashSet<value::InternedString>,
-    globals: HashMap<value::InternedString, Value>,
+    // Each global name gets a slot the first time the compiler sees it, and
+    // the global opcodes carry the slot so the VM doesn't have to hash names
+    global_slots: HashMap<value::InternedString, usize>,
+    globals: Vec<Option<Value>>,
+    // values the host program has asked to keep alive, see VM::root
+    host_roots: Vec<Option<Value>>,
     frames: Vec<CallFrame>,
     open_upvalues: Vec<ObjectRef<Upvalue>>,
     next_gc: usize,
+    incremental_gc: bool,
+    // the gray objects, while an incremental collection is in progress
+    gray: Option<gc::Worklist>,
+    chained_comparisons: bool,
+    strict_division: bool,
+    fresh_loop_variables: bool,
+    dump_after_gc: bool,
+    compat_clox: bool,
+    trace_execution: bool,
+    dump_chunks: bool,
+    dump_parse: bool,
+    trace_gc_roots: bool,
+    verbose_backtrace: bool,
+    pretty_errors: bool,
+    // the state of the xorshift generator behind random(), which starts from
+    // the same seed every time so that scripts are deterministic until they
+    // call seed()
+    random_state: u64,
+    // reused to pass arguments to natives, see call_value
+    native_args: Vec<Value>,
+    // the names passed to define_native, in the order they were defined
+    natives: Vec<String>,
+    // the globals declared with const, which is only known to the compiler,
+    // but has to last from one line of the REPL to the next
+    const_globals: HashSet<String>,
+    line_map: Option<LineMap>,
+    max_frames: usize,
+    max_stack: usize,
+    max_output_bytes: Option<usize>,
+    output_bytes: usize,
+    // counts down by one for every instruction run, over the life of the VM,
+    // as long as there is a limit (see run)
+    max_instructions: Option<u64>,
+    instructions_left: u64,
+    // how many calls to expect() have passed and failed, for --test
+    expects_passed: usize,
+    expects_failed: usize,
+    // set from outside to stop the script, see interrupt_handle
+    interrupt: Arc<AtomicBool>,
+    // where print writes to, which is only stdout for the command line
+    output: Box<dyn Write>,
+    // whether exit() ends the process, rather than stopping the script with
+    // RuntimeError::Exit for whoever is running the VM to deal with
+    exit_process: bool,
+    // the compiler's objects aren't rooted, so we can only collect while running
+    #[cfg(feature = "stress_gc")]
+    running: bool,
 }
 
 impl VM {
     fn new() -> Self {
-        Self {
+        Self::with_limits(FRAMES_MAX, STACK_MAX)
+    }
+
+    // The limits bound the depth of recursion and the number of values on the
+    // stack, beyond which a script fails with a stack overflow
+    fn with_limits(max_frames: usize, max_stack: usize) -> Self {
+        let mut vm = Self {
             stack: Vec::new(),
-            objects: Vec::new(),
+            objects: Heap::default(),
             strings: HashSet::new(),
-            globals: HashMap::new(),
+            global_slots: HashMap::new(),
+            globals: Vec::new(),
+            host_roots: Vec::new(),
             frames: Vec::new(),
             open_upvalues: Vec::new(),
             next_gc: get_allocated_bytes() * 2,
-        }
+            incremental_gc: false,
+            gray: None,
+            chained_comparisons: false,
+            strict_division: false,
+            fresh_loop_variables: false,
+            dump_after_gc: false,
+            compat_clox: false,
+            trace_execution: false,
+            dump_chunks: false,
+            dump_parse: false,
+            trace_gc_roots: false,
+            verbose_backtrace: false,
+            pretty_errors: false,
+            random_state: DEFAULT_RANDOM_SEED,
+            native_args: Vec::new(),
+            natives: Vec::new(),
+            const_globals: HashSet::new(),
+            line_map: None,
+            max_frames,
+            max_stack,
+            max_output_bytes: None,
+            output_bytes: 0,
+            max_instructions: None,
+            instructions_left: 0,
+            expects_passed: 0,
+            expects_failed: 0,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            output: Box::new(std::io::stdout()),
+            exit_process: false,
+            #[cfg(feature = "stress_gc")]
+            running: false,
+        };
+        vm.define_builtins();
+        vm
+    }
+
+    // The natives every script can use. The ones only for debugging and
+    // testing are defined by main() when they're asked for.
+    fn define_builtins(&mut self) {
+        self.define_native("clock", clock_native);
+        self.define_native("functionName", function_name_native);
+        self.define_native("assert", assert_native);
+        self.define_native("assertEqual", assert_equal_native);
+        self.define_native("assertNotEqual", assert_not_equal_native);
+        self.define_native("natives", natives_native);
+        self.define_native("sqrt", sqrt_native);
+        self.define_native("floor", floor_native);
+        self.define_native("ceil", ceil_native);
+        self.define_native("abs", abs_native);
+        self.define_native("pow", pow_native);
+        self.define_native("random", random_native);
+        self.define_native("seed", seed_native);
+        self.define_native("len", len_native);
+        self.define_native("substring", substring_native);
+        self.define_native("indexOf", index_of_native);
+        self.define_native("toUpper", to_upper_native);
+        self.define_native("toLower", to_lower_native);
+        self.define_native("readLine", read_line_native);
+        self.define_native("str", str_native);
+        self.define_native("num", num_native);
+        self.define_native("type", type_native);
+        self.define_native("exit", exit_native);
     }
 
     fn interpret_source(&mut self, source: &str) -> InterpretResult {
         let func = compiler::compile(source, self).map_err(VMError::CompileError)?;
+        self.interpret_function(func)
+    }
+
+    fn interpret_function(&mut self, func: Function) -> InterpretResult {
         let oref = manage(self, func);
         let closure_ref = manage(self, Closure::new(oref));
         let closure_root = closure_ref.upgrade().unwrap();
         self.stack.push(Value::Function(closure_ref));
-        self.call(closure_root, 0)?;
+        self.call(closure_root, 0, false)?;
+        #[cfg(feature = "stress_gc")]
+        {
+            self.running = true;
+        }
         let result = self.run();
+        #[cfg(feature = "stress_gc")]
+        {
+            self.running = false;
+        }
         if let Err(VMError::RuntimeError(ref e)) = result {
-            eprintln!("Runtime error: {}", e);
-            for frame in self.frames.iter().rev() {
-                let func_root = frame.closure.content.function.upgrade().unwrap().clone();
-                // don't subtract 1 from the offset because if we hit an error, the offset
-                // probably hasn't been updated anyway
-                let ip = IP::new(&func_root.content.chunk, frame.ip_offset);
-                if let Some(n) = ip.get_line() {
-                    eprint!("[line {}] in ", n);
-                } else {
-                    eprint!("[unknown line] in ");
-                }
-                match &frame
-                    .closure
-                    .content
-                    .function
-                    .upgrade()
-                    .unwrap()
-                    .content
-                    .name
-                {
-                    None => eprintln!("script"),
-                    Some(oref) => eprintln!("{}()", oref.upgrade().unwrap().content),
-                }
+            if let RuntimeError::Exit(_) = e {
+                // the script asked to stop, so there's nothing to report
+                self.reset_stack();
+                return result;
+            }
+            match e.clox_message() {
+                Some(message) if self.compat_clox => eprintln!("{}", message),
+                _ if self.compat_clox => eprintln!("{}", e),
+                _ if self.pretty_errors => eprintln!("{} {}", ansi::paint(ansi::RED, "error:"), e),
+                _ => eprintln!("Runtime error: {}", e),
             }
-            self.stack.clear();
+            eprint!("{}", self.backtrace());
+            self.reset_stack();
         }
         result
     }
 
-    fn peek_stack(&self, distance: usize) -> Value {
-        self.stack[self.stack.len() - 1 - distance].clone()
+    // After an error the frames have to go along with the values, or the next
+    // script to run would return into the one that failed. Closures made by
+    // that script can outlive it in globals, so they keep what they captured.
+    fn reset_stack(&mut self) {
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
+    }
+
+    // Lists the calls in progress, innermost first, one per line
+    fn backtrace(&self) -> String {
+        use std::fmt::Write;
+        let mut s = String::new();
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let func_root = frame.closure.content.function.upgrade().unwrap().clone();
+            // The frame that failed is still at the start of the instruction
+            // that failed, but the others are at the instruction after their
+            // call, which might be on a later line. The last byte of the call
+            // has the call's line.
+            let offset = match depth {
+                0 => frame.ip_offset,
+                _ => frame.ip_offset - 1,
+            };
+            let ip = IP::new(&func_root.content.chunk, offset);
+            let location = match ip.get_line() {
+                Some(n) => format!("[{}]", self.describe_line(n)),
+                None => "[unknown line]".to_owned(),
+            };
+            if self.pretty_errors {
+                write!(s, "  {} in ", ansi::paint(ansi::CYAN, &location)).unwrap();
+            } else {
+                write!(s, "{} in ", location).unwrap();
+            }
+            let function = frame.closure.content.function.upgrade().unwrap();
+            let args = if self.verbose_backtrace {
+                self.frame_arguments(frame, function.content.arity)
+            } else {
+                String::new()
+            };
+            match &function.content.name {
+                None if function.content.anonymous && self.verbose_backtrace => {
+                    writeln!(s, "anonymous function({})", args)
+                }
+                None if function.content.anonymous => writeln!(s, "anonymous function"),
+                None => writeln!(s, "script"),
+                Some(oref) => writeln!(s, "{}({})", oref.upgrade().unwrap().content, args),
+            }
+            .unwrap();
+        }
+        s
+    }
+
+    // For a verbose backtrace. These are the parameters as they are now, which
+    // won't be what the function was called with if it has assigned to them
+    // since.
+    fn frame_arguments(&self, frame: &CallFrame, arity: usize) -> String {
+        let args: Vec<String> = (1..=arity)
+            .map(|i| match self.stack.get(frame.base + i) {
+                Some(v) => value::printable_value(v.clone()),
+                None => "?".to_owned(),
+            })
+            .collect();
+        args.join(", ")
+    }
+
+    // Source lines are numbered after preprocessing, so map them back to the
+    // file they came from before showing them to the user
+    fn describe_line(&self, line: LineNo) -> String {
+        match &self.line_map {
+            None => format!("line {}", line),
+            Some(map) => map.describe(line),
+        }
+    }
+
+    // Copies a value that is staying on the stack. Use stack_ref instead when
+    // the value is only being looked at.
+    fn peek_stack(&self, distance: usize) -> ValueResult {
+        self.stack_ref(distance).cloned()
+    }
+
+    // Bytecode loaded from a file might not use the stack the way the compiler
+    // would have, so reaching past the bottom is an error rather than a panic
+    fn stack_ref(&self, distance: usize) -> Result<&Value, VMError> {
+        match self.stack.len().checked_sub(distance + 1) {
+            Some(index) => Ok(&self.stack[index]),
+            None => Err(VMError::RuntimeError(RuntimeError::StackUnderflow)),
+        }
+    }
+
+    // Where a local slot in the current frame is on the stack, as long as it's
+    // actually there
+    fn local_index(&self, slot: usize) -> Result<usize, VMError> {
+        let index = self.frames.last().unwrap().base + slot;
+        if index >= self.stack.len() {
+            return Err(VMError::RuntimeError(RuntimeError::CorruptBytecode));
+        }
+        Ok(index)
+    }
+
+    // Global slots come from the compiler, or from the loader for bytecode
+    // files, so one that doesn't exist means the code is corrupt
+    fn global(&mut self, slot: usize) -> Result<&mut Option<Value>, VMError> {
+        self.globals
+            .get_mut(slot)
+            .ok_or(VMError::RuntimeError(RuntimeError::CorruptBytecode))
+    }
+
+    // The name is only looked up to report a global that was never defined
+    fn get_global(&mut self, chunk: &Chunk, name: usize, slot: usize) -> InterpretResult {
+        match self.global(slot)?.clone() {
+            Some(v) => self.stack.push(v),
+            None => {
+                let name = chunk.constants[name].clone();
+                return rt(RuntimeError::UndefinedVariable(name.try_into()?));
+            }
+        }
+        Ok(())
+    }
+
+    // Assigning to a global that was never defined is an error, like reading it
+    fn set_global(&mut self, chunk: &Chunk, name: usize, slot: usize) -> InterpretResult {
+        let value = self.peek_stack(0)?;
+        match self.global(slot)? {
+            Some(global) => *global = value,
+            None => {
+                let name = chunk.constants[name].clone();
+                return rt(RuntimeError::UndefinedVariable(name.try_into()?));
+            }
+        }
+        Ok(())
+    }
+
+    fn current_upvalue(&self, slot: usize) -> Result<ObjectRoot<Upvalue>, VMError> {
+        let frame = self.frames.last().unwrap();
+        match frame.closure.content.upvalues.get(slot) {
+            Some(uv) => Ok(uv.upgrade().unwrap()),
+            None => Err(VMError::RuntimeError(RuntimeError::CorruptBytecode)),
+        }
     }
 
     fn pop_stack(&mut self) -> ValueResult {
@@ -417,7 +890,9 @@ impl VM {
                         if index < last {
                             return;
                         }
-                        *loc = UpvalueLocation::Heap(self.stack[index].clone());
+                        let value = self.stack[index].clone();
+                        self.write_barrier(&value);
+                        *loc = UpvalueLocation::Heap(value);
                         self.open_upvalues.pop();
                     }
                 }
@@ -425,7 +900,38 @@ impl VM {
         }
     }
 
+    // Instructions move the values they consume off the stack rather than
+    // cloning them. The clones that are left are the ones where a value really
+    // ends up in two places: GetLocal, GetUpvalue, GetGlobal, Dup and Tuck copy
+    // a value that stays where it is, the Set instructions leave the assigned
+    // value on the stack as the result of the assignment, Constant copies out
+    // of the chunk's pool, and natives get a copy of their arguments. Cloning a
+    // string or a function only bumps a Weak count and never allocates.
+    //
+    // The loop is compiled twice, and the copy that counts instructions is
+    // only used when there is a limit, so that scripts without one don't pay
+    // for it. The budget is counted down in a local rather than in the VM,
+    // which is much faster since it can stay in a register.
     fn run(&mut self) -> InterpretResult {
+        if self.max_instructions.is_none() {
+            return self.run_instructions::<false>(&mut 0);
+        }
+        let mut instructions_left = self.instructions_left;
+        let result = self.run_instructions::<true>(&mut instructions_left);
+        self.instructions_left = instructions_left;
+        result
+    }
+
+    #[cold]
+    fn instruction_limit_exceeded(&self) -> InterpretResult {
+        let limit = self.max_instructions.unwrap_or(u64::MAX);
+        rt(RuntimeError::InstructionLimitExceeded(limit))
+    }
+
+    fn run_instructions<const LIMITED: bool>(
+        &mut self,
+        instructions_left: &mut u64,
+    ) -> InterpretResult {
         macro_rules! binary_op {
             ($op:tt) => {{
                 let b: f64 = self.pop_stack()?.try_into()?;
@@ -434,8 +940,50 @@ impl VM {
          } };
         }
 
-        #[cfg(feature = "trace")]
-        {
+        // A zero divisor gives inf or NaN like any other IEEE arithmetic unless
+        // --strict-division makes it an error
+        macro_rules! division_op {
+            ($op:tt) => {{
+                let b: f64 = self.pop_stack()?.try_into()?;
+                let a: f64 = self.pop_stack()?.try_into()?;
+                if b == 0.0 && self.strict_division {
+                    return rt(RuntimeError::DivisionByZero);
+                }
+                self.stack.push((a $op b).into());
+            }};
+        }
+
+        // Strings compare by their contents, byte by byte, so every uppercase
+        // letter sorts before every lowercase one. Comparing a string with
+        // anything else is an error rather than falling back to numbers, and
+        // clox can't compare strings at all.
+        macro_rules! comparison_op {
+            ($op:tt) => {{
+                let b = self.pop_stack()?;
+                let a = self.pop_stack()?;
+                match (&a, &b) {
+                    (Value::String(sa), Value::String(sb)) if !self.compat_clox => {
+                        let sa = &sa.upgrade().unwrap().content;
+                        let sb = &sb.upgrade().unwrap().content;
+                        self.stack.push((sa $op sb).into());
+                    }
+                    (Value::Number(na), Value::Number(nb)) => self.stack.push((na $op nb).into()),
+                    (Value::String(_), _) | (_, Value::String(_)) => {
+                        return rt(RuntimeError::InvalidComparison(a.to_string(), b.to_string()));
+                    }
+                    _ => {
+                        // the type error for whichever one isn't a number
+                        let b: f64 = b.try_into()?;
+                        let a: f64 = a.try_into()?;
+                        self.stack.push((a $op b).into());
+                    }
+                }
+            }};
+        }
+
+        // the trace feature turns tracing on for good, without the runtime check
+        let trace = cfg!(feature = "trace") || self.trace_execution;
+        if trace {
             println!("Execution trace:")
         }
 
@@ -452,33 +1000,22 @@ impl VM {
         let mut ip = IP::new(&func_root.content.chunk, 0);
 
         loop {
-            // Performance-wise, we may want to delete this eventually
-            if !ip.valid() {
+            if LIMITED {
+                match instructions_left.checked_sub(1) {
+                    Some(n) => *instructions_left = n,
+                    None => return self.instruction_limit_exceeded(),
+                }
+            }
+
+            // Every chunk ends in a Return (the compiler always emits one and
+            // loxc won't load code without one), so execution can't run off
+            // the end, and release builds skip this check on every instruction
+            if (cfg!(debug_assertions) || trace) && !ip.valid() {
                 return rt(RuntimeError::EndOfChunk);
             }
 
-            #[cfg(feature = "trace")]
-            {
-                print!("          ");
-                if self.stack.len() == 0 {
-                    print!("<empty>");
-                } else {
-                    for v in &self.stack {
-                        print!("[ {} ]", v);
-                    }
-                }
-                print!(
-                    " (heap: {}, strings: {}, bytes: {})",
-                    self.objects.len(),
-                    self.strings.len(),
-                    crate::memory::get_allocated_bytes()
-                );
-                #[cfg(feature = "trace_globals")]
-                for (k, v) in &self.globals {
-                    print!(" {}={}", k, v);
-                }
-                println!("");
-                dis::disassemble_instruction(&mut ip.clone());
+            if trace {
+                self.trace_instruction(&func_root.content.chunk, ip.offset);
             }
 
             match OpCode::try_from(ip.read()) {
@@ -487,6 +1024,10 @@ impl VM {
                         let val = ip.read_constant();
                         self.stack.push(val);
                     }
+                    OpCode::ConstantLong => {
+                        let val = ip.read_constant_long();
+                        self.stack.push(val);
+                    }
                     OpCode::Nil => self.stack.push(Value::Nil),
                     OpCode::True => self.stack.push(Value::Bool(true)),
                     OpCode::False => self.stack.push(Value::Bool(false)),
@@ -495,25 +1036,17 @@ impl VM {
                         let b = self.pop_stack()?;
                         self.stack.push((a == b).into());
                     }
-                    OpCode::Greater => binary_op!(>),
-                    OpCode::Less => binary_op!(<),
+                    OpCode::Greater => comparison_op!(>),
+                    OpCode::Less => comparison_op!(<),
                     OpCode::Negate => {
                         // this is a lot of effort to make one test pass
-                        #[cfg(not(feature = "lox_errors"))]
-                        {
-                            let n: f64 = self.pop_stack()?.try_into()?;
-                            self.stack.push((-n).into());
-                        }
-                        #[cfg(feature = "lox_errors")]
-                        {
-                            let n: f64 = self.pop_stack()?.try_into().map_err(|vme| match vme {
-                                VMError::RuntimeError(RuntimeError::TypeError(ex, act, true)) => {
-                                    VMError::RuntimeError(RuntimeError::TypeError(ex, act, false))
-                                }
-                                _ => vme,
-                            })?;
-                            self.stack.push((-n).into());
-                        }
+                        let n: f64 = self.pop_stack()?.try_into().map_err(|vme| match vme {
+                            VMError::RuntimeError(RuntimeError::TypeError(ex, act, true)) => {
+                                VMError::RuntimeError(RuntimeError::TypeError(ex, act, false))
+                            }
+                            _ => vme,
+                        })?;
+                        self.stack.push((-n).into());
                     }
                     OpCode::Add => {
                         let a = self.pop_stack()?;
@@ -536,13 +1069,49 @@ impl VM {
                     }
                     OpCode::Subtract => binary_op!(-),
                     OpCode::Multiply => binary_op!(*),
-                    OpCode::Divide => binary_op!(/),
+                    OpCode::Divide => division_op!(/),
+                    // Like fmod() in C, the result takes the sign of the dividend
+                    OpCode::Modulo => division_op!(%),
+                    OpCode::Slice => {
+                        let end = self.pop_stack()?;
+                        let start = self.pop_stack()?;
+                        let s = match self.pop_stack()? {
+                            Value::String(oref) => oref.upgrade().unwrap(),
+                            v => return rt(RuntimeError::NotIndexable(v.type_name())),
+                        };
+                        // slices count characters rather than bytes
+                        let len = s.content.chars().count();
+                        let start = slice_index(start, 0, len)?;
+                        let end = slice_index(end, len, len)?;
+                        if start > end {
+                            return rt(RuntimeError::ReversedSlice(start, end));
+                        }
+                        let sliced: String =
+                            s.content.chars().skip(start).take(end - start).collect();
+                        let w = create_string(self, &sliced);
+                        self.stack.push(w.into());
+                    }
+                    // Turns the value into the string print would show, for string
+                    // interpolation
+                    OpCode::Stringify => {
+                        if !matches!(self.stack_ref(0)?, Value::String(_)) {
+                            let text = value::printable_value(self.pop_stack()?);
+                            let w = create_string(self, &text);
+                            self.stack.push(w.into());
+                        }
+                    }
                     OpCode::Not => {
                         let b = self.pop_stack()?.is_falsey();
                         self.stack.push(b.into());
                     }
                     OpCode::Print => {
-                        println!("{}", value::printable_value(self.pop_stack()?));
+                        let value = self.pop_stack()?;
+                        let text = if self.compat_clox {
+                            value::clox_printable_value(value)
+                        } else {
+                            value::printable_value(value)
+                        };
+                        self.write_output(&text)?;
                     }
                     OpCode::Jump => {
                         let offset = ip.read_short() as usize;
@@ -550,20 +1119,46 @@ impl VM {
                     }
                     OpCode::JumpIfFalse => {
                         let offset = ip.read_short() as usize;
-                        if self.peek_stack(0).is_falsey() {
+                        if self.stack_ref(0)?.is_falsey() {
+                            ip.offset += offset;
+                        }
+                    }
+                    OpCode::PopJumpIfFalse => {
+                        let offset = ip.read_short() as usize;
+                        if self.pop_stack()?.is_falsey() {
+                            ip.offset += offset;
+                        }
+                    }
+                    OpCode::PopJumpIfTrue => {
+                        let offset = ip.read_short() as usize;
+                        if !self.pop_stack()?.is_falsey() {
+                            ip.offset += offset;
+                        }
+                    }
+                    OpCode::JumpIfNotNil => {
+                        let offset = ip.read_short() as usize;
+                        if !matches!(self.stack_ref(0)?, Value::Nil) {
                             ip.offset += offset;
                         }
                     }
                     OpCode::Loop => {
                         let offset = ip.read_short() as usize;
                         ip.offset -= offset;
+                        self.check_interrupt()?;
                     }
-                    OpCode::Call => {
+                    OpCode::Call | OpCode::TailCall => {
                         let arg_count = ip.read() as usize;
-                        self.frames.last_mut().unwrap().ip_offset = ip.offset;
+                        self.check_interrupt()?;
                         let old_frames = self.frames.len();
-                        self.call_value(self.peek_stack(arg_count), arg_count)?;
+                        // the caller only returns to after the call once it has been
+                        // made, so an error making it is reported on the call's line
+                        let tail = matches!(instruction, OpCode::TailCall);
+                        self.call_value(arg_count, tail)?;
                         if self.frames.len() > old_frames {
+                            self.frames[old_frames - 1].ip_offset = ip.offset;
+                            if tail {
+                                self.replace_caller_frame();
+                            }
                             func_root = self
                                 .frames
                                 .last()
@@ -574,7 +1169,8 @@ impl VM {
                                 .upgrade()
                                 .unwrap()
                                 .clone();
-                            ip = IP::new(&func_root.content.chunk, 0);
+                            let start = self.frames.last().unwrap().ip_offset;
+                            ip = IP::new(&func_root.content.chunk, start);
                         }
                     }
                     OpCode::Return => {
@@ -596,22 +1192,23 @@ impl VM {
                             }
                         }
                     }
-                    OpCode::Closure => {
-                        let val = ip.read_constant();
+                    op @ (OpCode::Closure | OpCode::ClosureLong) => {
+                        let val = match op {
+                            OpCode::Closure => ip.read_constant(),
+                            _ => ip.read_constant_long(),
+                        };
                         if let Value::FunctionProto(function) = val {
                             let upvalue_count = function.upgrade().unwrap().content.upvalue_count;
                             let mut closure = Closure::new(function);
                             for _ in 0..upvalue_count {
                                 let is_local = ip.read() != 0;
-                                let index = ip.read() as usize;
+                                let index = ip.read_short() as usize;
                                 if is_local {
-                                    let frame_base = self.frames.last().unwrap().base;
-                                    let uv = self.capture_upvalue(frame_base + index);
+                                    let uv = self.capture_upvalue(self.local_index(index)?);
                                     closure.upvalues.push(uv);
                                 } else {
-                                    let frame = &self.frames.last().unwrap();
-                                    let uv = frame.closure.content.upvalues[index].clone();
-                                    closure.upvalues.push(uv);
+                                    let uv = self.current_upvalue(index)?;
+                                    closure.upvalues.push(Rc::downgrade(&uv));
                                 }
                             }
                             let closure_val = Value::Function(manage(self, closure));
@@ -619,79 +1216,151 @@ impl VM {
                         }
                     }
                     OpCode::CloseUpvalue => {
+                        self.stack_ref(0)?;
                         self.close_upvalues(self.stack.len() - 1);
                         self.pop_stack()?;
                     }
                     OpCode::Pop => {
                         self.pop_stack()?;
                     }
+                    // The items stay on the stack until the tuple has been
+                    // allocated, where a collection can still find them
+                    OpCode::BuildTuple => {
+                        let count = ip.read() as usize;
+                        let start = match self.stack.len().checked_sub(count) {
+                            Some(start) => start,
+                            None => return rt(RuntimeError::StackUnderflow),
+                        };
+                        let tuple = Tuple::new(self.stack[start..].to_vec());
+                        let value = Value::Tuple(manage(self, tuple));
+                        self.stack.truncate(start);
+                        self.stack.push(value);
+                    }
+                    OpCode::Unpack => {
+                        let count = ip.read() as usize;
+                        let tuple = match self.pop_stack()? {
+                            Value::Tuple(oref) => oref.upgrade().unwrap(),
+                            v => {
+                                return rt(RuntimeError::TypeError("tuple", v.to_string(), false));
+                            }
+                        };
+                        let items = &tuple.content.items;
+                        if items.len() != count {
+                            return rt(RuntimeError::UnpackMismatch(count, items.len()));
+                        }
+                        self.stack.extend_from_slice(items);
+                    }
+                    OpCode::PopN => {
+                        let count = ip.read() as usize;
+                        match self.stack.len().checked_sub(count) {
+                            Some(len) => self.stack.truncate(len),
+                            None => return rt(RuntimeError::StackUnderflow),
+                        }
+                    }
+                    OpCode::Dup => {
+                        let v = self.peek_stack(0)?;
+                        self.stack.push(v);
+                    }
+                    OpCode::Swap => {
+                        self.stack_ref(1)?;
+                        let len = self.stack.len();
+                        self.stack.swap(len - 1, len - 2);
+                    }
+                    OpCode::Rot3 => {
+                        // bring the third value from the top up to the top
+                        self.stack_ref(2)?;
+                        let v = self.stack.remove(self.stack.len() - 3);
+                        self.stack.push(v);
+                    }
+                    OpCode::Tuck => {
+                        // copy the top value to underneath the one below it
+                        self.stack_ref(1)?;
+                        let v = self.peek_stack(0)?;
+                        self.stack.insert(self.stack.len() - 2, v);
+                    }
                     OpCode::GetLocal => {
-                        let slot = ip.read();
-                        let frame = self.frames.last().unwrap();
-                        self.stack
-                            .push(self.stack[slot as usize + frame.base].clone());
+                        let index = self.local_index(ip.read() as usize)?;
+                        self.stack.push(self.stack[index].clone());
                     }
                     OpCode::SetLocal => {
-                        let slot = ip.read();
-                        let frame = self.frames.last().unwrap();
-                        self.stack[slot as usize + frame.base] = self.peek_stack(0).clone();
+                        let index = self.local_index(ip.read() as usize)?;
+                        self.stack[index] = self.peek_stack(0)?;
+                    }
+                    OpCode::GetLocalLong => {
+                        let index = self.local_index(ip.read_short() as usize)?;
+                        self.stack.push(self.stack[index].clone());
                     }
+                    OpCode::SetLocalLong => {
+                        let index = self.local_index(ip.read_short() as usize)?;
+                        self.stack[index] = self.peek_stack(0)?;
+                    }
+                    // The operands are the constant holding the name, which is only
+                    // needed for the error message, and then the slot. The long forms
+                    // differ only in taking two bytes for the constant.
                     OpCode::GetGlobal => {
-                        let val = ip.read_constant();
-                        let interned: InternedString = val.clone().try_into()?;
-                        match self.globals.get(&interned) {
-                            Some(v) => {
-                                self.stack.push(v.clone());
-                            }
-                            None => return rt(RuntimeError::UndefinedVariable(val.try_into()?)),
-                        }
+                        let name = ip.read() as usize;
+                        let slot = ip.read_short() as usize;
+                        self.get_global(ip.chunk, name, slot)?;
+                    }
+                    OpCode::GetGlobalLong => {
+                        let name = ip.read_short() as usize;
+                        let slot = ip.read_short() as usize;
+                        self.get_global(ip.chunk, name, slot)?;
                     }
                     OpCode::DefineGlobal => {
-                        let val = ip.read_constant();
-                        let interned: InternedString = val.try_into()?;
-                        self.globals.insert(interned, self.peek_stack(0));
-                        self.pop_stack()?;
+                        ip.read();
+                        let slot = ip.read_short() as usize;
+                        let value = self.pop_stack()?;
+                        *self.global(slot)? = Some(value);
+                    }
+                    OpCode::DefineGlobalLong => {
+                        ip.read_short();
+                        let slot = ip.read_short() as usize;
+                        let value = self.pop_stack()?;
+                        *self.global(slot)? = Some(value);
                     }
                     OpCode::SetGlobal => {
-                        let val = ip.read_constant();
-                        let interned: InternedString = val.clone().try_into()?;
-                        if self.globals.contains_key(&interned) {
-                            self.globals.insert(interned, self.peek_stack(0));
-                        } else {
-                            return rt(RuntimeError::UndefinedVariable(val.try_into()?));
-                        }
+                        let name = ip.read() as usize;
+                        let slot = ip.read_short() as usize;
+                        self.set_global(ip.chunk, name, slot)?;
+                    }
+                    OpCode::SetGlobalLong => {
+                        let name = ip.read_short() as usize;
+                        let slot = ip.read_short() as usize;
+                        self.set_global(ip.chunk, name, slot)?;
                     }
                     OpCode::GetUpvalue => {
-                        let slot = ip.read() as usize;
-                        let frame = &self.frames.last().unwrap();
-                        match &*frame.closure.content.upvalues[slot]
-                            .upgrade()
-                            .unwrap()
-                            .content
-                            .location
-                            .borrow()
-                        {
-                            UpvalueLocation::Stack(index) => {
-                                self.stack.push(self.stack[*index].clone())
-                            }
-                            UpvalueLocation::Heap(value) => self.stack.push(value.clone()),
-                        }
+                        let uv_root = self.current_upvalue(ip.read() as usize)?;
+                        let value = match &*uv_root.content.location.borrow() {
+                            UpvalueLocation::Stack(index) => self.stack[*index].clone(),
+                            UpvalueLocation::Heap(value) => value.clone(),
+                        };
+                        self.stack.push(value);
                     }
                     OpCode::SetUpvalue => {
-                        let slot = ip.read() as usize;
-                        let frame = &self.frames.last().unwrap();
-                        let uv_root = frame.closure.content.upvalues[slot].upgrade().unwrap();
+                        let uv_root = self.current_upvalue(ip.read() as usize)?;
                         let mut loc = uv_root.content.location.borrow_mut();
                         match *loc {
-                            UpvalueLocation::Stack(index) => self.stack[index] = self.peek_stack(0),
+                            UpvalueLocation::Stack(index) => {
+                                self.stack[index] = self.peek_stack(0)?
+                            }
                             UpvalueLocation::Heap(_) => {
-                                *loc = UpvalueLocation::Heap(self.peek_stack(0))
+                                let value = self.peek_stack(0)?;
+                                self.write_barrier(&value);
+                                *loc = UpvalueLocation::Heap(value)
                             }
                         }
                     }
                 },
                 Err(_) => return rt(RuntimeError::UnknownOpcode),
             }
+            // Most instructions grow the stack by one value at most, and none by
+            // more than 255 (Unpack, or a call filling in missing arguments), so
+            // checking here catches an overflow before it has gone far past the
+            // limit
+            if self.stack.len() > self.max_stack {
+                return rt(RuntimeError::StackOverflow);
+            }
             self.frames.last_mut().unwrap().ip_offset = ip.offset;
             let current_bytes;
             #[cfg(not(feature = "stress_gc"))]
@@ -702,19 +1371,36 @@ impl VM {
             {
                 current_bytes = self.next_gc;
             }
-            if current_bytes >= self.next_gc {
-                self.collect_garbage();
-                self.next_gc = get_allocated_bytes() * 2;
+            if self.gray.is_some() {
+                self.gc_step();
+            } else if current_bytes >= self.next_gc {
+                if self.incremental_gc {
+                    self.begin_gc_cycle();
+                } else {
+                    self.collect_garbage();
+                    self.next_gc = get_allocated_bytes() * 2;
+                }
             }
         }
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), VMError> {
-        match callee {
-            Value::Function(oref) => return self.call(oref.upgrade().unwrap(), arg_count),
+    // The callee is below its arguments on the stack
+    fn call_value(&mut self, arg_count: usize, tail: bool) -> Result<(), VMError> {
+        match self.stack_ref(arg_count)? {
+            Value::Function(oref) => self.call(oref.upgrade().unwrap(), arg_count, tail),
             Value::Native(oref) => {
-                let args: &[Value] = &self.stack[self.stack.len() - arg_count..];
-                let result = (oref.upgrade().unwrap().content.function)(arg_count, args);
+                let function = oref.upgrade().unwrap().content.function;
+                // The native can't borrow its arguments from the stack while it
+                // has the VM too, so they're copied into a buffer that is kept
+                // from one call to the next rather than a new Vec each time.
+                // They stay on the stack as well, where the GC can see them.
+                let mut args = std::mem::take(&mut self.native_args);
+                args.clear();
+                args.extend_from_slice(&self.stack[self.stack.len() - arg_count..]);
+                let result = function(self, arg_count, &args);
+                args.clear();
+                self.native_args = args;
+                let result = result?;
                 self.stack.truncate(self.stack.len() - arg_count - 1);
                 self.stack.push(result);
                 Ok(())
@@ -723,27 +1409,197 @@ impl VM {
         }
     }
 
-    fn call(&mut self, closure: ObjectRoot<Closure>, arg_count: usize) -> Result<(), VMError> {
-        let function = closure.content.function.upgrade().unwrap();
-        if arg_count != function.content.arity {
-            return rt(RuntimeError::WrongArity(function.content.arity, arg_count));
+    // After a tail call, the caller has nothing left to do but return what the
+    // callee returns, so the callee's frame can take the caller's place. This
+    // keeps tail recursion from running out of frames.
+    fn replace_caller_frame(&mut self) {
+        let callee = self.frames.pop().unwrap();
+        let caller = self.frames.pop().unwrap();
+        self.close_upvalues(caller.base);
+        self.stack.drain(caller.base..callee.base);
+        self.frames.push(CallFrame {
+            base: caller.base,
+            ..callee
+        });
+    }
+
+    // A tail call's frame is about to replace its caller's, so it can be made
+    // even when the frames are at the limit
+    fn call(
+        &mut self,
+        closure: ObjectRoot<Closure>,
+        arg_count: usize,
+        tail: bool,
+    ) -> Result<(), VMError> {
+        let function = &closure.content.function.upgrade().unwrap().content;
+        let optional = function.defaults.len().saturating_sub(1);
+        let required = function.arity - optional;
+        if arg_count > function.arity {
+            return rt(RuntimeError::WrongArity(function.arity, arg_count));
         }
-        if self.frames.len() == 64 {
+        if arg_count < required {
+            return rt(RuntimeError::WrongArity(required, arg_count));
+        }
+        if self.frames.len() == self.max_frames && !tail {
             return rt(RuntimeError::StackOverflow);
         }
+        // make room for the missing arguments, which are filled in by skipping
+        // only the defaults for the ones that were passed
+        for _ in arg_count..function.arity {
+            self.stack.push(Value::Nil);
+        }
         let frame = CallFrame {
+            ip_offset: function
+                .defaults
+                .get(arg_count - required)
+                .copied()
+                .unwrap_or(0),
+            base: self.stack.len() - function.arity - 1,
             closure,
-            ip_offset: 0,
-            base: self.stack.len() - arg_count - 1,
         };
         self.frames.push(frame);
         Ok(())
     }
 
+    fn trace_instruction(&self, chunk: &Chunk, offset: usize) {
+        print!("          ");
+        if self.stack.is_empty() {
+            print!("<empty>");
+        } else {
+            for v in &self.stack {
+                print!("[ {} ]", v);
+            }
+        }
+        print!(
+            " (heap: {}, strings: {}, bytes: {})",
+            self.objects.len(),
+            self.strings.len(),
+            crate::memory::get_allocated_bytes()
+        );
+        #[cfg(feature = "trace_globals")]
+        for (k, v) in self.defined_globals() {
+            print!(" {}={}", k, v);
+        }
+        println!();
+        // a fresh TracingIP knows the line even when the fast IP is in use
+        dis::disassemble_instruction(&mut TracingIP::new(chunk, offset));
+    }
+
+    // Everything the script prints goes through here, so that a runaway loop
+    // can't produce more than max_output_bytes (counting the newlines)
+    fn write_output(&mut self, text: &str) -> InterpretResult {
+        let bytes = text.len() + 1;
+        if let Some(limit) = self.max_output_bytes {
+            if self.output_bytes + bytes > limit {
+                return rt(RuntimeError::OutputLimitExceeded(limit));
+            }
+        }
+        self.output_bytes += bytes;
+        writeln!(self.output, "{}", text).expect("Error writing output.");
+        Ok(())
+    }
+
+    // Stops any script run from now on with a runtime error once the VM has
+    // run this many instructions in total
+    fn set_instruction_limit(&mut self, limit: u64) {
+        self.max_instructions = Some(limit);
+        self.instructions_left = limit;
+    }
+
+    // Another thread (or a signal handler) can set the flag this returns to
+    // stop the running script with a runtime error. The VM only looks at it
+    // when it loops back or makes a call, so this is cooperative rather than
+    // preemptive: a native that never returns can't be interrupted. The flag
+    // is cleared once the VM has stopped, so the VM can be used again.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    fn check_interrupt(&self) -> InterpretResult {
+        if self.interrupt.load(Ordering::Relaxed) {
+            self.interrupt.store(false, Ordering::Relaxed);
+            return rt(RuntimeError::Interrupted);
+        }
+        Ok(())
+    }
+
+    // Natives don't get a frame of their own, so the top frame is still at the
+    // call that is running one
+    fn caller_line(&self) -> String {
+        let frame = self.frames.last().unwrap();
+        let function = frame.closure.content.function.upgrade().unwrap();
+        match IP::new(&function.content.chunk, frame.ip_offset).get_line() {
+            Some(n) => self.describe_line(n),
+            None => "unknown line".to_owned(),
+        }
+    }
+
+    // Sends what the script prints somewhere other than stdout. To read it
+    // back afterwards, pass a writer that shares its buffer with the caller.
+    #[cfg_attr(not(test), allow(dead_code))]
+    fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // Lists a function along with every function declared inside it, in the
+    // order the dump feature prints them as they finish compiling
+    fn disassemble(&self, function: &Function) -> String {
+        let mut s = String::new();
+        for constant in &function.chunk.constants {
+            if let Value::FunctionProto(oref) = constant {
+                s.push_str(&self.disassemble(&oref.upgrade().unwrap().content));
+            }
+        }
+        s.push_str(&function.disassemble());
+        s
+    }
+
+    // Finds the slot for a global, giving it a new one if it hasn't got one
+    fn global_slot(&mut self, name: InternedString) -> usize {
+        let next = self.globals.len();
+        let slot = *self.global_slots.entry(name).or_insert(next);
+        if slot == next {
+            self.globals.push(None);
+        }
+        slot
+    }
+
+    // The globals that have values, leaving out names that have a slot but
+    // were never defined
+    fn defined_globals(&self) -> impl Iterator<Item = (&InternedString, &Value)> {
+        self.global_slots
+            .iter()
+            .filter_map(move |(k, &slot)| self.globals[slot].as_ref().map(|v| (k, v)))
+    }
+
+    fn dump_globals(&self) {
+        let mut globals: Vec<(&InternedString, &Value)> = self.defined_globals().collect();
+        globals.sort_by(|(a, _), (b, _)| a.0.content.cmp(&b.0.content));
+        println!("== globals ==");
+        for (name, v) in globals {
+            println!(
+                "{}: {} = {}",
+                name,
+                v.type_name(),
+                value::printable_value(v.clone())
+            );
+        }
+    }
+
     fn define_native(&mut self, name: &str, function: NativeFn) {
         let interned = InternedString(create_string(self, name).upgrade().unwrap());
-        let value = Value::Native(manage::<Native>(self, Native::new(function)));
-        self.globals.insert(interned, value);
+        let value = Value::Native(manage::<Native>(self, Native::new(name, function)));
+        let slot = self.global_slot(interned);
+        self.globals[slot] = Some(value);
+        self.natives.push(name.to_owned());
+    }
+
+    // Strings have to be interned, which is why this isn't a From impl. Like
+    // any Value, the result only holds a weak reference, so it won't survive
+    // a collection unless it is somewhere the VM can see, such as a global or
+    // the stack. Don't keep it across anything else that allocates.
+    fn value_from_str(&mut self, s: &str) -> Value {
+        create_string(self, s).into()
     }
 }
 
@@ -755,49 +1611,1315 @@ fn clock() -> u128 {
         .as_millis()
 }
 
-fn clock_native(_arg_count: usize, _args: &[Value]) -> Value {
-    Value::Number(clock() as f64)
+fn clock_native(_vm: &mut VM, _arg_count: usize, _args: &[Value]) -> ValueResult {
+    Ok(Value::Number(clock() as f64))
+}
+
+fn function_name_native(vm: &mut VM, _arg_count: usize, _args: &[Value]) -> ValueResult {
+    // natives don't get a frame of their own, so the top frame is the caller's
+    let function = vm.frames.last().unwrap().closure.content.function.upgrade();
+    let function = function.unwrap();
+    let name = match &function.content.name {
+        None if function.content.anonymous => "<anonymous>".to_owned(),
+        None => "<script>".to_owned(),
+        Some(oref) => oref.upgrade().unwrap().content.clone(),
+    };
+    Ok(vm.value_from_str(&name))
+}
+
+// There are no lists yet, so the interned strings come back as a single
+// string, sorted and formatted the way a list of them would print
+fn interned_strings_native(vm: &mut VM, _arg_count: usize, _args: &[Value]) -> ValueResult {
+    let mut contents: Vec<String> = vm
+        .strings
+        .iter()
+        .map(|interned| format!("{:?}", interned.0.content))
+        .collect();
+    contents.sort();
+    let description = format!("[{}]", contents.join(", "));
+    Ok(vm.value_from_str(&description))
+}
+
+// Like internedStrings(), this returns a string that reads like a list
+fn natives_native(vm: &mut VM, _arg_count: usize, _args: &[Value]) -> ValueResult {
+    let names: Vec<String> = vm.natives.iter().map(|n| format!("{:?}", n)).collect();
+    let description = format!("[{}]", names.join(", "));
+    Ok(vm.value_from_str(&description))
+}
+
+// assert(cond) or assert(cond, message), where the message can be any value
+fn assert_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if !(1..=2).contains(&arg_count) {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            arg_count.clamp(1, 2),
+            arg_count,
+        )));
+    }
+    if args[0].is_falsey() {
+        let message = match args.get(1) {
+            Some(message) => value::printable_value(message.clone()),
+            None => format!("condition was {}", args[0]),
+        };
+        return Err(VMError::RuntimeError(RuntimeError::AssertionFailed(
+            message,
+        )));
+    }
+    Ok(Value::Nil)
+}
+
+// == compares tuples item by item, so this works on them as well
+fn assert_equal_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 2 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            2, arg_count,
+        )));
+    }
+    if args[0] != args[1] {
+        return Err(VMError::RuntimeError(RuntimeError::AssertionFailed(
+            format!("expected {} but got {}", args[1], args[0]),
+        )));
+    }
+    Ok(Value::Nil)
+}
+
+// Unlike assertEqual, a failure doesn't stop the script, so that --test can
+// report every expectation that failed
+fn expect_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 2 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            2, arg_count,
+        )));
+    }
+    if args[0] == args[1] {
+        vm.expects_passed += 1;
+    } else {
+        vm.expects_failed += 1;
+        let message = format!(
+            "[{}] expect failed: expected {} but got {}",
+            vm.caller_line(),
+            args[1],
+            args[0]
+        );
+        vm.write_output(&message)?;
+    }
+    Ok(Value::Nil)
+}
+
+fn assert_not_equal_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 2 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            2, arg_count,
+        )));
+    }
+    if args[0] == args[1] {
+        return Err(VMError::RuntimeError(RuntimeError::AssertionFailed(
+            format!("expected anything but {}", args[1]),
+        )));
+    }
+    Ok(Value::Nil)
+}
+
+// Checks the arguments to a numeric native, with the same type error as
+// the arithmetic operators would give
+fn number_args(arg_count: usize, args: &[Value], expected: usize) -> Result<Vec<f64>, VMError> {
+    if arg_count != expected {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            expected, arg_count,
+        )));
+    }
+    args.iter()
+        .map(|v| match v {
+            Value::Number(n) => Ok(*n),
+            _ => Err(VMError::RuntimeError(RuntimeError::TypeError(
+                "number",
+                v.to_string(),
+                expected > 1,
+            ))),
+        })
+        .collect()
+}
+
+// Like the arithmetic operators, sqrt() of a negative number is nan rather
+// than an error
+fn sqrt_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 1)?;
+    Ok(Value::Number(n[0].sqrt()))
+}
+
+fn floor_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 1)?;
+    Ok(Value::Number(n[0].floor()))
+}
+
+fn ceil_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 1)?;
+    Ok(Value::Number(n[0].ceil()))
+}
+
+fn abs_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 1)?;
+    Ok(Value::Number(n[0].abs()))
+}
+
+fn pow_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 2)?;
+    Ok(Value::Number(n[0].powf(n[1])))
+}
+
+// xorshift64* never leaves the all-zero state, so that can't be a seed
+const DEFAULT_RANDOM_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+// A number in [0, 1), using the top 53 bits of the next xorshift64* output
+fn random_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    number_args(arg_count, args, 0)?;
+    let mut x = vm.random_state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    vm.random_state = x;
+    let bits = x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+    Ok(Value::Number(bits as f64 / (1u64 << 53) as f64))
+}
+
+// Any number will do as a seed, including fractions and negative numbers,
+// since only its bits are used
+fn seed_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    let n = number_args(arg_count, args, 1)?;
+    vm.random_state = match n[0].to_bits() {
+        0 => DEFAULT_RANDOM_SEED,
+        bits => bits,
+    };
+    Ok(Value::Nil)
+}
+
+// Counts characters rather than bytes, to agree with slicing. Strings are the
+// only values with a length until there are lists.
+fn len_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    match &args[0] {
+        Value::String(oref) => Ok(Value::Number(
+            oref.upgrade().unwrap().content.chars().count() as f64,
+        )),
+        v => Err(VMError::RuntimeError(RuntimeError::TypeError(
+            "string",
+            v.to_string(),
+            false,
+        ))),
+    }
+}
+
+// The string natives all count in characters, like slicing and len(). The
+// arguments are still on the stack while a native runs, so the strings they
+// allocate can't cause them to be collected.
+fn substring_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 3 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            3, arg_count,
+        )));
+    }
+    let s = String::try_from(args[0].clone())?;
+    let len = s.chars().count();
+    let start = substring_index(&args[1], len)?;
+    let end = substring_index(&args[2], len)?;
+    if start > end {
+        return Err(VMError::RuntimeError(RuntimeError::ReversedSlice(
+            start, end,
+        )));
+    }
+    let sub: String = s.chars().skip(start).take(end - start).collect();
+    Ok(vm.value_from_str(&sub))
+}
+
+fn substring_index(v: &Value, len: usize) -> Result<usize, VMError> {
+    match v {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 && *n as usize <= len => Ok(*n as usize),
+        Value::Number(_) => Err(VMError::RuntimeError(RuntimeError::IndexOutOfBounds(
+            v.to_string(),
+            len,
+        ))),
+        _ => Err(VMError::RuntimeError(RuntimeError::TypeError(
+            "number",
+            v.to_string(),
+            false,
+        ))),
+    }
+}
+
+// Returns -1 if the needle isn't there
+fn index_of_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 2 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            2, arg_count,
+        )));
+    }
+    let s = String::try_from(args[0].clone())?;
+    let needle = String::try_from(args[1].clone())?;
+    Ok(Value::Number(match s.find(&needle) {
+        Some(byte) => s[..byte].chars().count() as f64,
+        None => -1.0,
+    }))
+}
+
+fn to_upper_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    let s = String::try_from(args[0].clone())?;
+    Ok(vm.value_from_str(&s.to_uppercase()))
+}
+
+fn to_lower_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    let s = String::try_from(args[0].clone())?;
+    Ok(vm.value_from_str(&s.to_lowercase()))
+}
+
+fn exit_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    let code = match args[0] {
+        Value::Number(n) if (0.0..=255.0).contains(&n) && n.fract() == 0.0 => n as u8,
+        _ => {
+            return Err(VMError::RuntimeError(RuntimeError::InvalidExitCode(
+                args[0].to_string(),
+            )))
+        }
+    };
+    if vm.exit_process {
+        vm.output.flush().unwrap();
+        std::process::exit(code as i32);
+    }
+    Err(VMError::RuntimeError(RuntimeError::Exit(code)))
+}
+
+// The same text that print would show, so str("a") is just "a"
+fn str_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    let text = value::printable_value(args[0].clone());
+    Ok(vm.value_from_str(&text))
+}
+
+// The name of the value's type, for scripts that need to check what they were
+// given
+fn type_native(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    Ok(vm.value_from_str(args[0].type_name()))
+}
+
+// Returns nil rather than an error if the string isn't a number, so that
+// scripts can check input from readLine(). Rust would also parse "nan" and
+// "inf", which aren't numbers a Lox program could write, so they give nil too.
+fn num_native(_vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult {
+    if arg_count != 1 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            1, arg_count,
+        )));
+    }
+    let s = String::try_from(args[0].clone())?;
+    Ok(match s.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => Value::Number(n),
+        _ => Value::Nil,
+    })
+}
+
+// Blocks until a whole line has been read from stdin, and returns it without
+// its line ending, or nil at the end of input. A script read from stdin has
+// used up all of the input already, so there this always returns nil.
+fn read_line_native(vm: &mut VM, arg_count: usize, _args: &[Value]) -> ValueResult {
+    if arg_count != 0 {
+        return Err(VMError::RuntimeError(RuntimeError::WrongArity(
+            0, arg_count,
+        )));
+    }
+    // anything printed as a prompt has to appear before we wait for input
+    vm.output.flush().unwrap();
+    let mut line = String::new();
+    match std::io::stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            Ok(vm.value_from_str(line))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    path: Option<String>,
+    dump_globals: bool,
+    chained_comparisons: bool,
+    strict_division: bool,
+    fresh_loop_variables: bool,
+    dump_after_gc: bool,
+    compat_clox: bool,
+    max_output_bytes: Option<usize>,
+    max_instructions: Option<u64>,
+    test: bool,
+    debug_natives: bool,
+    eval: Option<String>,
+    bench_ip: bool,
+    trace: bool,
+    dump: bool,
+    dump_parse: bool,
+    compile: bool,
+    output: Option<String>,
+    run_bytecode: Option<String>,
+    trace_gc_roots: bool,
+    verbose_backtrace: bool,
+    color: Option<bool>,
+    incremental_gc: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: rlox [options] [path]");
+    eprintln!("       rlox [options] -e code");
+    eprintln!("       rlox [options] --compile path -o output");
+    eprintln!("       rlox [options] --run bytecode");
+    eprintln!("a path of - reads the script from standard input");
+    eprintln!("options:");
+    eprintln!("  --dump-globals          print every global after running the script");
+    eprintln!("  --chained-comparisons   treat a < b < c as a < b and b < c");
+    eprintln!("  --strict-division       make dividing by zero a runtime error");
+    eprintln!("  --fresh-loop-variables  give each iteration of a for loop its own variable");
+    eprintln!("  --dump-after-gc         count the surviving objects after each collection");
+    eprintln!("  --incremental-gc        mark objects a few at a time between instructions");
+    eprintln!("  --trace-gc-roots        list the roots the collector marks from");
+    eprintln!("  --verbose-backtrace     show the arguments of each call in a backtrace");
+    eprintln!("  --compat-clox           match the behaviour of clox from the book exactly");
+    eprintln!("  --pretty-errors         show errors in color with the source line");
+    eprintln!("  --color                 the same as --pretty-errors");
+    eprintln!("  --no-color              show errors plainly even on a terminal");
+    eprintln!("  --max-output-bytes N    fail once the script prints more than N bytes");
+    eprintln!("  --max-instructions N    fail once the script runs more than N instructions");
+    eprintln!(
+        "  --test                  define expect(actual, expected) and report how many failed"
+    );
+    eprintln!("  --debug-natives         define internedStrings() to inspect the VM");
+    eprintln!("  --dump                  disassemble each function after compiling or loading it");
+    eprintln!("  --dump-parse            print the statements and expressions as they are parsed");
+    eprintln!("  --trace                 print the stack and each instruction as it runs");
+    eprintln!("  --bench-ip              time both instruction pointers on a script's code");
+    std::process::exit(64);
+}
+
+fn parse_args() -> Options {
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dump-globals" => options.dump_globals = true,
+            "--chained-comparisons" => options.chained_comparisons = true,
+            "--strict-division" => options.strict_division = true,
+            "--fresh-loop-variables" => options.fresh_loop_variables = true,
+            "--dump-after-gc" => options.dump_after_gc = true,
+            "--trace-gc-roots" => options.trace_gc_roots = true,
+            "--verbose-backtrace" => options.verbose_backtrace = true,
+            "--incremental-gc" => options.incremental_gc = true,
+            "-e" => match args.next() {
+                Some(code) if options.eval.is_none() => options.eval = Some(code),
+                _ => usage(),
+            },
+            "--compat-clox" => options.compat_clox = true,
+            "--pretty-errors" | "--color" => options.color = Some(true),
+            "--no-color" => options.color = Some(false),
+            "--debug-natives" => options.debug_natives = true,
+            "--test" => options.test = true,
+            "--bench-ip" => options.bench_ip = true,
+            "--dump" => options.dump = true,
+            "--dump-parse" => options.dump_parse = true,
+            "--trace" => options.trace = true,
+            "--compile" => options.compile = true,
+            "-o" => match args.next() {
+                Some(path) if options.output.is_none() => options.output = Some(path),
+                _ => usage(),
+            },
+            "--run" => match args.next() {
+                Some(path) if options.run_bytecode.is_none() => options.run_bytecode = Some(path),
+                _ => usage(),
+            },
+            "--max-output-bytes" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.max_output_bytes = Some(n),
+                None => usage(),
+            },
+            "--max-instructions" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) => options.max_instructions = Some(n),
+                None => usage(),
+            },
+            _ if arg.starts_with("--") => usage(),
+            _ if options.path.is_none() => options.path = Some(arg),
+            _ => usage(),
+        }
+    }
+    if options.eval.is_some() && options.path.is_some() {
+        usage();
+    }
+    if options.bench_ip && options.eval.is_none() && options.path.is_none() {
+        usage();
+    }
+    if options.compile != options.output.is_some() || (options.compile && options.path.is_none()) {
+        usage();
+    }
+    if options.run_bytecode.is_some() && (options.path.is_some() || options.eval.is_some()) {
+        usage();
+    }
+    options
 }
 
 fn main() {
+    let options = parse_args();
     let mut vm = VM::new();
-    vm.define_native("clock", clock_native);
-    let args: Vec<String> = std::env::args().collect();
-    let argc = args.len();
-    if argc == 1 {
-        repl(&mut vm);
-    } else if argc == 2 {
-        run_file(&mut vm, &args[1])
-    } else {
-        eprintln!("usage: rlox [path]");
-        std::process::exit(64);
+    if options.debug_natives {
+        vm.define_native("internedStrings", interned_strings_native);
+    }
+    if options.test {
+        vm.define_native("expect", expect_native);
+    }
+    // clox has no chained comparisons or strict division, so --compat-clox
+    // overrides them. The rest is up to the VM and the compiler, which check
+    // compat_clox themselves. Some things need nothing doing: clox's limits
+    // of 64 frames and 64 * 256 stack slots are the defaults here too (tail
+    // calls, which would let recursion go deeper, are turned off instead), and
+    // + concatenates strings in the same order, failing for anything other
+    // than two numbers or two strings just as clox does, where only the
+    // message is different.
+    vm.chained_comparisons = options.chained_comparisons && !options.compat_clox;
+    vm.strict_division = options.strict_division && !options.compat_clox;
+    vm.fresh_loop_variables = options.fresh_loop_variables && !options.compat_clox;
+    vm.dump_after_gc = options.dump_after_gc;
+    vm.trace_gc_roots = options.trace_gc_roots;
+    vm.verbose_backtrace = options.verbose_backtrace;
+    vm.incremental_gc = options.incremental_gc;
+    vm.compat_clox = options.compat_clox;
+    // the clox error format takes priority, since it's there to be matched
+    vm.pretty_errors = ansi::use_color(options.color) && !options.compat_clox;
+    vm.max_output_bytes = options.max_output_bytes;
+    if let Some(limit) = options.max_instructions {
+        vm.set_instruction_limit(limit);
+    }
+    vm.exit_process = true;
+    vm.trace_execution = options.trace;
+    vm.dump_chunks = options.dump;
+    vm.dump_parse = options.dump_parse;
+    if options.bench_ip {
+        bench_ip(&mut vm, &options);
+    }
+    if options.compile {
+        compile_file(&mut vm, &options);
+    }
+    if let Some(path) = &options.run_bytecode {
+        run_bytecode(&mut vm, path, &options);
+    }
+    if let Some(code) = &options.eval {
+        run_source(&mut vm, code, &options);
+    }
+    match options.path.as_deref() {
+        None => repl(&mut vm),
+        Some("-") => run_stdin(&mut vm, &options),
+        Some(path) => run_file(&mut vm, path, &options),
     }
 }
 
+// Input is incomplete if it has unclosed brackets or strings or ends with an
+// operator, in which case the REPL waits for more lines before compiling it
+fn is_incomplete(source: &str) -> bool {
+    let mut depth: i32 = 0;
+    let mut last = TokenType::EOF;
+    for token in tokenize(source) {
+        match token.ttype {
+            TokenType::EOF => break,
+            TokenType::UnterminatedStringError => return true,
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+            // a string part closes one ${ if it starts with } and opens another
+            // if it ends with ${
+            TokenType::StringLiteral | TokenType::Interpolation => {
+                if token.content.unwrap().starts_with('}') {
+                    depth -= 1;
+                }
+                if token.ttype == TokenType::Interpolation {
+                    depth += 1;
+                }
+            }
+            _ => (),
+        }
+        last = token.ttype;
+    }
+    // too many closing brackets is an error for the compiler to report
+    depth > 0
+        || matches!(
+            last,
+            TokenType::Comma
+                | TokenType::Dot
+                | TokenType::DotDot
+                | TokenType::Colon
+                | TokenType::Question
+                | TokenType::QuestionQuestion
+                | TokenType::Minus
+                | TokenType::MinusEqual
+                | TokenType::Plus
+                | TokenType::PlusEqual
+                | TokenType::Slash
+                | TokenType::SlashEqual
+                | TokenType::Star
+                | TokenType::StarEqual
+                | TokenType::Percent
+                | TokenType::Bang
+                | TokenType::BangEqual
+                | TokenType::Equal
+                | TokenType::EqualEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+                | TokenType::And
+                | TokenType::Or
+        )
+}
+
+// Ctrl-C stops whatever the REPL is running, but still quits at the prompt
 fn repl(vm: &mut VM) {
+    let interrupt = vm.interrupt_handle();
+    let running = Arc::new(AtomicBool::new(false));
+    let handler_running = Arc::clone(&running);
+    let handler = ctrlc::set_handler(move || {
+        if handler_running.load(Ordering::Relaxed) {
+            interrupt.store(true, Ordering::Relaxed);
+        } else {
+            std::process::exit(130);
+        }
+    });
+    if let Err(e) = handler {
+        eprintln!("Could not handle Ctrl-C: {}", e);
+    }
+    let interrupt = vm.interrupt_handle();
+    let mut buffer = String::new();
     print!("> ");
     std::io::stdout().flush().expect("Error writing to stdout.");
     for line in std::io::stdin().lock().lines() {
-        // Following line silences the error since we already handled it
-        vm.interpret_source(&line.unwrap()).unwrap_or(());
-        print!("> ");
+        buffer.push_str(&line.unwrap());
+        buffer.push('\n');
+        if is_incomplete(&buffer) {
+            print!("... ");
+        } else {
+            running.store(true, Ordering::Relaxed);
+            // Following line silences the error since we already handled it
+            vm.interpret_source(&buffer).unwrap_or(());
+            running.store(false, Ordering::Relaxed);
+            // a Ctrl-C the script finished before noticing is for this line only
+            interrupt.store(false, Ordering::Relaxed);
+            buffer.clear();
+            print!("> ");
+        }
         std::io::stdout().flush().expect("Error writing to stdout.");
     }
+    // Ctrl-D in the middle of a statement throws away what we had of it
+    println!();
+}
+
+fn run_file(vm: &mut VM, path: &str, options: &Options) -> ! {
+    let (source, line_map) = include::preprocess(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        match e {
+            include::IncludeError::Unreadable(_) => std::process::exit(74),
+            _ => std::process::exit(65),
+        }
+    });
+    vm.line_map = Some(line_map);
+    run_source(vm, &source, options);
+}
+
+fn bench_ip(vm: &mut VM, options: &Options) -> ! {
+    let source = match (&options.eval, options.path.as_deref()) {
+        (Some(code), _) => code.clone(),
+        (None, Some("-")) => read_stdin(),
+        (None, Some(path)) => std::fs::read_to_string(path).unwrap_or_else(|_| {
+            eprintln!("Could not read input file: {}", path);
+            std::process::exit(74);
+        }),
+        (None, None) => unreachable!(),
+    };
+    std::process::exit(bench::bench_ip(vm, &source));
+}
+
+// Scripts from standard input or -e don't go through the @include
+// preprocessor, since there is no file for included paths to be relative to
+fn run_stdin(vm: &mut VM, options: &Options) -> ! {
+    let source = read_stdin();
+    run_source(vm, &source, options);
+}
+
+fn read_stdin() -> String {
+    let mut source = String::new();
+    if let Err(e) = std::io::stdin().read_to_string(&mut source) {
+        eprintln!("Could not read standard input: {}", e);
+        std::process::exit(74);
+    }
+    source
+}
+
+fn run_source(vm: &mut VM, source: &str, options: &Options) -> ! {
+    let result = vm.interpret_source(source);
+    finish(vm, result, options);
+}
+
+// Line numbers in a compiled file count lines of the source after @include
+// has been expanded, since the line map isn't saved along with it
+fn compile_file(vm: &mut VM, options: &Options) -> ! {
+    let path = options.path.as_deref().unwrap();
+    let output = options.output.as_deref().unwrap();
+    let (source, _) = include::preprocess(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        match e {
+            include::IncludeError::Unreadable(_) => std::process::exit(74),
+            _ => std::process::exit(65),
+        }
+    });
+    let function = compiler::compile(&source, vm).unwrap_or_else(|_| std::process::exit(65));
+    let written = std::fs::File::create(output).and_then(|file| {
+        let mut out = std::io::BufWriter::new(file);
+        loxc::write_program(&function, &mut out)?;
+        out.flush()
+    });
+    if let Err(e) = written {
+        eprintln!("Could not write output file {}: {}", output, e);
+        std::process::exit(74);
+    }
+    std::process::exit(0);
 }
 
-fn run_file(vm: &mut VM, path: &str) -> ! {
-    let source = std::fs::read_to_string(path).unwrap_or_else(|_| {
+fn run_bytecode(vm: &mut VM, path: &str, options: &Options) -> ! {
+    let file = std::fs::File::open(path).unwrap_or_else(|_| {
         eprintln!("Could not read input file: {}", path);
-        std::process::exit(74)
+        std::process::exit(74);
+    });
+    let function = loxc::read_program(vm, &mut std::io::BufReader::new(file)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        match e {
+            loxc::LoxcError::Io(_) => std::process::exit(74),
+            _ => std::process::exit(65),
+        }
     });
-    let exitcode = match vm.interpret_source(&source) {
+    // a program read back from a file was never compiled, so --dump has to
+    // list it here instead
+    if vm.dump_chunks {
+        print!("{}", vm.disassemble(&function));
+    }
+    let result = vm.interpret_function(function);
+    finish(vm, result, options);
+}
+
+fn finish(vm: &mut VM, result: InterpretResult, options: &Options) -> ! {
+    if options.dump_globals {
+        vm.dump_globals();
+    }
+    if options.test {
+        let summary = format!("{} passed, {} failed", vm.expects_passed, vm.expects_failed);
+        writeln!(vm.output, "{}", summary).expect("Error writing output.");
+    }
+    let exitcode = match result {
+        Ok(()) if vm.expects_failed > 0 => 1,
         Ok(()) => 0,
         Err(VMError::CompileError(_)) => 65,
+        Err(VMError::RuntimeError(RuntimeError::Exit(code))) => code as i32,
         Err(VMError::RuntimeError(_)) => 70,
     };
     std::process::exit(exitcode);
 }
 
+// An omitted (nil) index means the start or end of the string
+fn slice_index(v: Value, default: usize, len: usize) -> Result<usize, VMError> {
+    let index = match v {
+        Value::Nil => default,
+        Value::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+        _ => {
+            return Err(VMError::RuntimeError(RuntimeError::InvalidSliceIndex(
+                v.to_string(),
+            )))
+        }
+    };
+    if index > len {
+        return Err(VMError::RuntimeError(RuntimeError::SliceOutOfRange(
+            index, len,
+        )));
+    }
+    Ok(index)
+}
+
 fn rt(e: RuntimeError) -> InterpretResult {
     Err(VMError::RuntimeError(e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Somewhere for print to write to that the test can still read from once
+    // the VM has it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn constants_are_reused() {
+        let mut vm = VM::new();
+        let mut chunk = Chunk::new();
+        for i in 0..1000 {
+            assert_eq!(chunk.add_long_constant((i as f64).into()).unwrap(), i);
+        }
+        for i in 0..1000 {
+            assert_eq!(chunk.add_long_constant((i as f64).into()).unwrap(), i);
+        }
+        assert_eq!(chunk.constants.len(), 1000);
+
+        let negative_zero = chunk.add_long_constant((-0.0).into()).unwrap();
+        assert_ne!(negative_zero, 0);
+        let nan = chunk.add_long_constant(f64::NAN.into()).unwrap();
+        assert_eq!(chunk.add_long_constant(f64::NAN.into()).unwrap(), nan);
+
+        let s = chunk
+            .add_long_constant(create_string(&mut vm, "s").into())
+            .unwrap();
+        assert_eq!(s, 1002);
+        let t = chunk
+            .add_long_constant(create_string(&mut vm, "t").into())
+            .unwrap();
+        assert_eq!(t, 1003);
+        let s_again = chunk
+            .add_long_constant(create_string(&mut vm, "s").into())
+            .unwrap();
+        assert_eq!(s_again, s);
+        assert_eq!(chunk.constants.len(), 1004);
+    }
+
+    #[test]
+    fn another_thread_can_interrupt_a_script() {
+        let mut vm = VM::new();
+        let interrupt = vm.interrupt_handle();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        let result = vm.interpret_source("while (true) {}");
+        stopper.join().unwrap();
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::Interrupted))
+        ));
+        // the flag has been cleared, so the VM can carry on
+        assert!(vm
+            .interpret_source("var i = 0; while (i < 10) i = i + 1;")
+            .is_ok());
+    }
+
+    #[test]
+    fn new_vm_has_the_builtins() {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source("print natives();\nassert(sqrt(16) == 4);")
+            .unwrap();
+        assert_eq!(
+            output.text(),
+            "[\"clock\", \"functionName\", \"assert\", \"assertEqual\", \
+             \"assertNotEqual\", \"natives\", \"sqrt\", \"floor\", \"ceil\", \"abs\", \
+             \"pow\", \"random\", \"seed\", \"len\", \"substring\", \"indexOf\", \
+             \"toUpper\", \"toLower\", \"readLine\", \"str\", \"num\", \"type\", \
+             \"exit\"]\n"
+        );
+    }
+
+    #[test]
+    fn new_vm_has_assert() {
+        let mut vm = VM::new();
+        let result = vm.interpret_source("assert(1 > 2, \"wrong way round\");");
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::AssertionFailed(m))) if m == "wrong way round"
+        ));
+    }
+
+    #[test]
+    fn exit_stops_the_script_without_ending_the_process() {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        let result = vm.interpret_source("print 1;\nexit(3);\nprint 2;");
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::Exit(3)))
+        ));
+        assert_eq!(output.text(), "1\n");
+        // the VM is left ready to run something else
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert!(vm.interpret_source("print 4;").is_ok());
+        assert_eq!(output.text(), "1\n4\n");
+    }
+
+    #[test]
+    fn print_writes_to_the_output() {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source("print 1 + 2;\nprint \"a\" + \"b\";\nprint nil;")
+            .unwrap();
+        assert_eq!(output.text(), "3\nab\nnil\n");
+    }
+
+    #[test]
+    fn rooted_values_survive_collection() {
+        let mut vm = VM::new();
+        let kept = create_string(&mut vm, "kept");
+        let dropped = create_string(&mut vm, "dropped");
+        let handle = vm.root(kept.clone().into());
+        vm.collect_garbage();
+        assert_eq!(kept.upgrade().unwrap().content, "kept");
+        assert!(dropped.upgrade().is_none());
+
+        assert!(matches!(vm.unroot(handle), Value::String(_)));
+        vm.collect_garbage();
+        assert!(kept.upgrade().is_none());
+    }
+
+    #[test]
+    fn interned_strings_shrink_after_collection() {
+        let mut vm = VM::new();
+        vm.define_native("internedStrings", interned_strings_native);
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        let setup = "var keep = \"kept\" + \"!\";\n\
+                     var drop = \"temp\" + \"orary\";\n\
+                     drop = nil;\n\
+                     print internedStrings();";
+        vm.interpret_source(setup).unwrap();
+        assert!(output.text().contains("\"kept!\""));
+        assert!(output.text().contains("\"temporary\""));
+
+        vm.collect_garbage();
+        vm.interpret_source("print internedStrings();").unwrap();
+        let after = output.text().lines().last().unwrap().to_owned();
+        assert!(after.contains("\"kept!\""));
+        assert!(!after.contains("\"temporary\""));
+    }
+
+    #[test]
+    fn disassemble_lists_nested_functions_first() {
+        let mut vm = VM::new();
+        let function = compiler::compile(
+            "fun outer() {\n  fun inner() { return 1; }\n  return inner;\n}",
+            &mut vm,
+        )
+        .unwrap();
+        assert_eq!(
+            vm.disassemble(&function),
+            "\
+== <fn inner/0> ==
+    2 0000 CONSTANT         0    1
+    | 0002 RETURN
+    | 0003 NIL
+    | 0004 RETURN
+== <fn outer/0> ==
+    2 0000 CLOSURE          0    <fn inner/0>
+    3 0002 GET_LOCAL        1   
+    | 0004 RETURN
+    4 0005 NIL
+    | 0006 RETURN
+== <script> ==
+    4 0000 CLOSURE          1    <fn outer/0>
+    | 0002 DEFINE_GLOBAL    0    \"outer\" (slot 23)
+    | 0006 NIL
+    | 0007 RETURN
+"
+        );
+    }
+
+    // Runs a script the way interpret_function does, but leaves the frames
+    // in place when it fails so the test can look at them
+    fn run_without_reset(vm: &mut VM, source: &str) -> InterpretResult {
+        let function = compiler::compile(source, vm).unwrap();
+        let function = manage(vm, function);
+        let closure_ref = manage(vm, Closure::new(function));
+        let closure_root = closure_ref.upgrade().unwrap();
+        vm.stack.push(Value::Function(closure_ref));
+        vm.call(closure_root, 0, false)?;
+        vm.run()
+    }
+
+    #[test]
+    fn frame_limit_bounds_recursion() {
+        let source = "fun f(n) {\n  if (n > 0) f(n - 1);\n}\n";
+        // the script's own frame counts towards the limit
+        let mut vm = VM::with_limits(8, STACK_MAX);
+        assert!(vm.interpret_source(&format!("{}f(6);", source)).is_ok());
+        let result = run_without_reset(&mut vm, &format!("{}f(7);", source));
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::StackOverflow))
+        ));
+        assert_eq!(vm.frames.len(), 8);
+        assert_eq!(
+            vm.backtrace(),
+            format!("{}[line 4] in script\n", "[line 2] in f()\n".repeat(7))
+        );
+    }
+
+    #[test]
+    fn tail_call_at_the_frame_limit() {
+        let source = "fun h() { return \"done\"; }\nfun g() { return h(); }\n";
+        // the script and g fill both frames, and h replaces g
+        let mut vm = VM::with_limits(2, STACK_MAX);
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        assert!(vm
+            .interpret_source(&format!("{}print g();", source))
+            .is_ok());
+        assert_eq!(output.text(), "done\n");
+        // but an ordinary call from a function needs a third
+        let result = vm.interpret_source(&format!("{}fun k() {{ return 1 + h(); }}\nk();", source));
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::StackOverflow))
+        ));
+    }
+
+    #[test]
+    fn stack_limit_bounds_values() {
+        let mut vm = VM::with_limits(FRAMES_MAX, 16);
+        assert!(vm
+            .interpret_source("var t = (1, 2, 3, 4, 5, 6, 7, 8);")
+            .is_ok());
+        let result = vm.interpret_source("fun f(a, b, c, d, e, f, g, h) { return (a, b, c, d, e, f, g, h); } print f(1, 2, 3, 4, 5, 6, 7, 8);");
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::StackOverflow))
+        ));
+    }
+
+    // Each frame here needs more than its share of STACK_MAX, so the value
+    // stack runs out before the frames do
+    #[test]
+    fn wide_recursion_overflows_the_stack_before_the_frames() {
+        let params: Vec<_> = (0..250).map(|i| format!("p{}", i)).collect();
+        let locals: String = (0..10).map(|i| format!("var l{} = {};\n", i, i)).collect();
+        let source = format!(
+            "fun f({}) {{\n{}f({});\n}}\nf({});",
+            params.join(", "),
+            locals,
+            params.join(", "),
+            vec!["1"; 250].join(", ")
+        );
+        let mut vm = VM::new();
+        let result = run_without_reset(&mut vm, &source);
+        assert!(matches!(
+            result,
+            Err(VMError::RuntimeError(RuntimeError::StackOverflow))
+        ));
+        assert!(vm.frames.len() < FRAMES_MAX);
+    }
+
+    // Pushes 1, 2 and 3, runs the given instructions, and prints the top
+    // count values on the stack as a tuple
+    fn run_instructions(code: Vec<OpCode>, count: u8) -> String {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        let mut function = Function::new_in_vm(&mut vm, None, 0);
+        let chunk = &mut function.chunk;
+        for n in 1..=3 {
+            let index = chunk.add_long_constant((n as f64).into()).unwrap() as u8;
+            chunk.write(OpCode::Constant.into(), 1);
+            chunk.write(index, 1);
+        }
+        for op in code {
+            chunk.write(op.into(), 1);
+        }
+        for byte in [OpCode::BuildTuple.into(), count, OpCode::Print.into()] {
+            chunk.write(byte, 1);
+        }
+        chunk.write(OpCode::Nil.into(), 1);
+        chunk.write(OpCode::Return.into(), 1);
+        vm.interpret_function(function).unwrap();
+        output.text()
+    }
+
+    #[test]
+    fn swap_and_rot3_reorder_the_stack() {
+        assert_eq!(run_instructions(vec![OpCode::Swap], 3), "(1, 3, 2)\n");
+        assert_eq!(run_instructions(vec![OpCode::Rot3], 3), "(2, 3, 1)\n");
+        assert_eq!(
+            run_instructions(vec![OpCode::Swap, OpCode::Rot3], 3),
+            "(3, 2, 1)\n"
+        );
+    }
+
+    fn integer_error(result: Result<i64, VMError>) -> String {
+        match result {
+            Err(VMError::RuntimeError(RuntimeError::TypeError("integer", value, _))) => value,
+            _ => panic!("expected an integer type error"),
+        }
+    }
+
+    #[test]
+    fn values_convert_to_and_from_rust_types() {
+        let mut vm = VM::new();
+        assert!(Value::from(-7) == Value::Number(-7.0));
+        assert_eq!(i64::try_from(Value::from(-7)).unwrap(), -7);
+        assert_eq!(
+            i64::try_from(Value::Number(2e15)).unwrap(),
+            2_000_000_000_000_000
+        );
+        assert_eq!(integer_error(i64::try_from(Value::Number(1.5))), "1.5");
+        assert_eq!(integer_error(i64::try_from(Value::Number(1e19))), "1e+19");
+        assert_eq!(integer_error(i64::try_from(Value::Number(f64::NAN))), "nan");
+        assert_eq!(integer_error(i64::try_from(Value::Nil)), "nil");
+
+        assert_eq!(usize::try_from(Value::Number(3.0)).unwrap(), 3);
+        assert!(usize::try_from(Value::Number(-1.0)).is_err());
+        assert!(usize::try_from(Value::Number(0.5)).is_err());
+        assert!(usize::try_from(Value::Number(f64::INFINITY)).is_err());
+
+        let s = vm.value_from_str("hello");
+        assert!(s == vm.value_from_str("hello"));
+        assert_eq!(String::try_from(s).unwrap(), "hello");
+    }
+
+    #[test]
+    fn dup_copies_the_top_of_the_stack() {
+        assert_eq!(run_instructions(vec![OpCode::Dup], 4), "(1, 2, 3, 3)\n");
+        assert_eq!(
+            run_instructions(vec![OpCode::Dup, OpCode::Rot3], 4),
+            "(1, 3, 3, 2)\n"
+        );
+    }
+
+    #[test]
+    fn switch_evaluates_its_subject_once() {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source(
+            "var calls = 0;\n\
+             fun subject() { calls = calls + 1; return 3; }\n\
+             switch (subject()) {\n\
+               case 1: print \"one\";\n\
+               case 3: print \"three\";\n\
+               default: print \"other\";\n\
+             }\n\
+             print calls;",
+        )
+        .unwrap();
+        assert_eq!(output.text(), "three\n1\n");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn conditional_leaves_one_value() {
+        let mut vm = VM::new();
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source(
+            "print true ? 1 : 2;\n\
+             print false ? 1 : false ? 2 : 3;\n\
+             print false ? 1 : true ? 2 : 3;\n\
+             var a = true ? \"a\" : \"b\";\n\
+             print a;",
+        )
+        .unwrap();
+        assert_eq!(output.text(), "1\n3\n2\na\n");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn line_lookup_matches_a_linear_scan() {
+        let mut chunk = Chunk::new();
+        // runs of different lengths, with lines going backwards sometimes
+        // as they do for the increment clause of a for loop
+        let mut line = 10;
+        for i in 0..2000 {
+            if i % 7 == 0 || i % 11 == 0 {
+                line = if i % 5 == 0 { line - 1 } else { line + 3 };
+            }
+            chunk.write(OpCode::Nil.into(), line);
+        }
+        assert!(chunk.lines.len() > 300);
+        for offset in 0..chunk.code.len() {
+            let linear = chunk
+                .lines
+                .iter()
+                .rev()
+                .find(|&&(start, _)| start <= offset)
+                .map(|&(_, line)| line);
+            assert_eq!(IP::new(&chunk, offset).get_line(), linear);
+        }
+    }
+
+    // Runs bytes that the compiler would never produce, without checking them
+    // first the way loading a file does
+    fn run_garbage(code: &[u8]) -> RuntimeError {
+        let mut vm = VM::new();
+        let mut function = Function::new_in_vm(&mut vm, None, 0);
+        for &byte in code {
+            function.chunk.write(byte, 1);
+        }
+        match vm.interpret_function(function) {
+            Err(VMError::RuntimeError(e)) => e,
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn garbage_bytecode_is_an_error_not_a_panic() {
+        let pop: u8 = OpCode::Pop.into();
+        let nil: u8 = OpCode::Nil.into();
+        assert!(matches!(
+            run_garbage(&[pop, pop, pop]),
+            RuntimeError::StackUnderflow
+        ));
+        assert!(matches!(
+            run_garbage(&[OpCode::PopN.into(), 10]),
+            RuntimeError::StackUnderflow
+        ));
+        assert!(matches!(
+            run_garbage(&[OpCode::Swap.into()]),
+            RuntimeError::StackUnderflow
+        ));
+        assert!(matches!(
+            run_garbage(&[OpCode::GetLocal.into(), 200]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[nil, OpCode::SetLocal.into(), 9]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[OpCode::GetUpvalue.into(), 0]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[nil, 250]),
+            RuntimeError::UnknownOpcode
+        ));
+        assert!(matches!(
+            run_garbage(&[OpCode::GetGlobal.into(), 0, 0x7f, 0xff]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[nil, OpCode::SetGlobal.into(), 0, 0x7f, 0xff]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[nil, OpCode::DefineGlobal.into(), 0, 0x7f, 0xff]),
+            RuntimeError::CorruptBytecode
+        ));
+        assert!(matches!(
+            run_garbage(&[nil, OpCode::DefineGlobalLong.into(), 0, 0, 0x7f, 0xff]),
+            RuntimeError::CorruptBytecode
+        ));
+    }
+
+    #[test]
+    fn three_hundred_globals() {
+        let mut source = String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var g{} = {};\n", i, i));
+        }
+        let names: Vec<_> = (0..300).map(|i| format!("g{}", i)).collect();
+        source.push_str(&format!("print {};\n", names.join(" + ")));
+        source.push_str("g299 = g299 + 1;\nprint g299;");
+
+        let mut vm = VM::new();
+        let function = compiler::compile(&source, &mut vm).unwrap();
+        let listing = function.disassemble();
+        assert!(listing.contains("DEFINE_GLOBAL_LONG"));
+        assert!(listing.contains("GET_GLOBAL_LONG"));
+        assert!(listing.contains("SET_GLOBAL_LONG"));
+
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source(&source).unwrap();
+        assert_eq!(output.text(), "44850\n300\n");
+    }
+
+    // the functions come after 300 other constants in both chunks, so both
+    // closures need the long form, and the inner one captures a local
+    #[test]
+    fn closure_past_256_constants() {
+        let prints: String = (0..300).map(|i| format!("print {};\n", i)).collect();
+        let source = format!(
+            "{}fun outer() {{\n{}var x = \"captured\";\nfun inner() {{ return x; }}\nreturn inner;\n}}\nprint outer()();",
+            prints, prints
+        );
+        let mut vm = VM::new();
+        let function = compiler::compile(&source, &mut vm).unwrap();
+        let listing = vm.disassemble(&function);
+        assert_eq!(listing.matches("CLOSURE_LONG").count(), 2);
+        assert!(listing.contains("|                local 1"));
+        assert_eq!(function.chunk.verify(&[]), Ok(()));
+
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source(&source).unwrap();
+        assert!(output.text().ends_with("298\n299\ncaptured\n"));
+    }
+
+    #[test]
+    fn closure_captures_local_past_slot_255() {
+        let mut source = String::from("fun f() {\n");
+        for i in 0..400 {
+            source.push_str(&format!("var v{} = {};\n", i, i));
+        }
+        let names: Vec<_> = (0..400).map(|i| format!("v{}", i)).collect();
+        source.push_str(&format!("print {};\n", names.join(" + ")));
+        source.push_str("fun get() { return v399 + v300; }\n");
+        source.push_str("v399 = v399 + 1;\nreturn get;\n}\nprint f()();");
+
+        let mut vm = VM::new();
+        let function = compiler::compile(&source, &mut vm).unwrap();
+        let listing = vm.disassemble(&function);
+        // slot 0 is the function itself
+        assert!(listing.contains("|                local 400"));
+        assert!(listing.contains("|                local 301"));
+
+        let output = Output::default();
+        vm.set_output(Box::new(output.clone()));
+        vm.interpret_source(&source).unwrap();
+        assert_eq!(output.text(), "79800\n700\n");
+    }
+}
@@ -1,7 +1,7 @@
 use crate::compiler::Compiler;
-use crate::scanner::TokenType;
-use crate::value::create_string;
-use crate::OpCode;
+use crate::scanner::{Token, TokenType};
+use crate::value::{create_string, FunctionType, Value};
+use crate::{CompileError, OpCode};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::convert::TryFrom;
 
@@ -9,16 +9,19 @@ use std::convert::TryFrom;
 #[repr(usize)]
 pub enum Precedence {
     None = 0,
-    Assignment = 1,
-    Or = 2,
-    And = 3,
-    Equality = 4,
-    Comparison = 5,
-    Term = 6,
-    Factor = 7,
-    Unary = 8,
-    Call = 9,
-    Primary = 10,
+    Comma = 1,
+    Assignment = 2,
+    Conditional = 3,
+    Coalesce = 4,
+    Or = 5,
+    And = 6,
+    Equality = 7,
+    Comparison = 8,
+    Term = 9,
+    Factor = 10,
+    Unary = 11,
+    Call = 12,
+    Primary = 13,
 }
 
 type ParseFn = fn(&mut Compiler<'_, '_>, bool);
@@ -46,6 +49,15 @@ pub fn get_rule(ttype: TokenType) -> ParseRule {
             infix: Some(call),
             precedence: Precedence::Call,
         },
+        TokenType::LeftBrace => ParseRule {
+            prefix: Some(block_expression),
+            ..ParseRule::default()
+        },
+        TokenType::LeftBracket => ParseRule {
+            prefix: None,
+            infix: Some(slice),
+            precedence: Precedence::Call,
+        },
         TokenType::Minus => ParseRule {
             prefix: Some(unary),
             infix: Some(binary),
@@ -56,6 +68,16 @@ pub fn get_rule(ttype: TokenType) -> ParseRule {
             infix: Some(binary),
             precedence: Precedence::Term,
         },
+        TokenType::MinusMinus => ParseRule {
+            prefix: None,
+            infix: Some(invalid_increment),
+            precedence: Precedence::Call,
+        },
+        TokenType::PlusPlus => ParseRule {
+            prefix: None,
+            infix: Some(invalid_increment),
+            precedence: Precedence::Call,
+        },
         TokenType::Slash => ParseRule {
             prefix: None,
             infix: Some(binary),
@@ -66,6 +88,11 @@ pub fn get_rule(ttype: TokenType) -> ParseRule {
             infix: Some(binary),
             precedence: Precedence::Factor,
         },
+        TokenType::Percent => ParseRule {
+            prefix: None,
+            infix: Some(binary),
+            precedence: Precedence::Factor,
+        },
         TokenType::Bang => ParseRule {
             prefix: Some(unary),
             ..ParseRule::default()
@@ -108,6 +135,10 @@ pub fn get_rule(ttype: TokenType) -> ParseRule {
             prefix: Some(string),
             ..ParseRule::default()
         },
+        TokenType::Interpolation => ParseRule {
+            prefix: Some(interpolation),
+            ..ParseRule::default()
+        },
         TokenType::NumberLiteral => ParseRule {
             prefix: Some(number),
             ..ParseRule::default()
@@ -134,110 +165,331 @@ pub fn get_rule(ttype: TokenType) -> ParseRule {
             infix: Some(or_op),
             precedence: Precedence::Or,
         },
+        TokenType::QuestionQuestion => ParseRule {
+            prefix: None,
+            infix: Some(coalesce),
+            precedence: Precedence::Coalesce,
+        },
+        TokenType::Comma => ParseRule {
+            prefix: None,
+            infix: Some(comma),
+            precedence: Precedence::Comma,
+        },
+        TokenType::Question => ParseRule {
+            prefix: None,
+            infix: Some(conditional),
+            precedence: Precedence::Conditional,
+        },
+        TokenType::Fun => ParseRule {
+            prefix: Some(lambda),
+            ..ParseRule::default()
+        },
         _ => ParseRule::default(),
     }
 }
 
+// Brackets around a list with a comma at the top level, as in (a, b), make a
+// tuple rather than grouping the comma operator, wherever they are
 fn grouping(c: &mut Compiler, _can_assign: bool) {
-    c.expression();
-    c.consume(TokenType::RightParen, "Expect ')' after expression.")
+    c.assignment_expression();
+    if c.match_token(TokenType::Comma) {
+        c.finish_tuple();
+    } else {
+        c.consume(TokenType::RightParen, "Expect ')' after expression.")
+    }
 }
 
 fn unary(c: &mut Compiler, _can_assign: bool) {
     let token = c.previous.as_ref().unwrap();
     let op_type = token.ttype;
     let line = token.line;
+    let start = c.code_len();
+    let constants = c.constants_len();
     c.parse_precedence(Precedence::Unary);
     match op_type {
-        TokenType::Minus => c.emit_byte_with_line(OpCode::Negate.into(), line),
+        TokenType::Minus => {
+            c.emit_byte_with_line(OpCode::Negate.into(), line);
+            c.fold_constants(start, constants);
+        }
         TokenType::Bang => c.emit_byte_with_line(OpCode::Not.into(), line),
         _ => unreachable!(),
     }
 }
 
+// The scanner only makes ++ and -- single tokens straight after a name, and
+// variable() deals with them there, so one that gets here follows a name that
+// couldn't be resolved
+fn invalid_increment(c: &mut Compiler, _can_assign: bool) {
+    match c.previous.as_ref().unwrap().ttype {
+        TokenType::PlusPlus => c.error("Invalid increment target.", CompileError::ParseError),
+        _ => c.error("Invalid decrement target.", CompileError::ParseError),
+    }
+}
+
+fn is_comparison(ttype: TokenType) -> bool {
+    matches!(
+        ttype,
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual
+    )
+}
+
 fn binary(c: &mut Compiler, _can_assign: bool) {
     let ttype = c.previous.as_ref().unwrap().ttype;
+    if c.vm.chained_comparisons && is_comparison(ttype) {
+        chained_comparison(c, ttype);
+        return;
+    }
+    // parsing the right operand changes operand_start
+    let left_start = c.operand_start;
+    let left_constants = c.operand_constants;
     let precedence: usize = get_rule(ttype).precedence.into();
+    c.cc.temporaries += 1;
     c.parse_precedence(Precedence::try_from(precedence + 1).unwrap());
+    c.cc.temporaries -= 1;
+    // a < b < c would compare a bool with c and fail at runtime, so catch it
+    // here instead unless we're being clox, which compiles it happily
+    if is_comparison(ttype) && is_comparison(c.current.as_ref().unwrap().ttype) && !c.vm.compat_clox
+    {
+        c.short_error_at_current(CompileError::ChainedComparison);
+    }
     match ttype {
         TokenType::BangEqual => c.emit_bytes(OpCode::Equal.into(), OpCode::Not.into()),
         TokenType::EqualEqual => c.emit_byte(OpCode::Equal.into()),
-        TokenType::Greater => c.emit_byte(OpCode::Greater.into()),
-        TokenType::GreaterEqual => c.emit_bytes(OpCode::Less.into(), OpCode::Not.into()),
-        TokenType::Less => c.emit_byte(OpCode::Less.into()),
-        TokenType::LessEqual => c.emit_bytes(OpCode::Greater.into(), OpCode::Not.into()),
+        tt if is_comparison(tt) => emit_comparison(c, tt),
         TokenType::Plus => c.emit_byte(OpCode::Add.into()),
         TokenType::Minus => c.emit_byte(OpCode::Subtract.into()),
         TokenType::Star => c.emit_byte(OpCode::Multiply.into()),
         TokenType::Slash => c.emit_byte(OpCode::Divide.into()),
+        TokenType::Percent => c.emit_byte(OpCode::Modulo.into()),
+        _ => unreachable!(),
+    }
+    c.fold_constants(left_start, left_constants);
+}
+
+fn emit_comparison(c: &mut Compiler, ttype: TokenType) {
+    match ttype {
+        TokenType::Greater => c.emit_byte(OpCode::Greater.into()),
+        TokenType::GreaterEqual => c.emit_bytes(OpCode::Less.into(), OpCode::Not.into()),
+        TokenType::Less => c.emit_byte(OpCode::Less.into()),
+        TokenType::LessEqual => c.emit_bytes(OpCode::Greater.into(), OpCode::Not.into()),
         _ => unreachable!(),
     }
 }
 
+// With chained comparisons turned on, a < b < c means a < b and b < c, except
+// that b is only evaluated once: we tuck a copy of it under the left operand so
+// it is still on the stack for the next comparison if this one succeeds.
+fn chained_comparison(c: &mut Compiler, first: TokenType) {
+    let mut ttype = first;
+    let mut fail_jumps = Vec::new();
+    loop {
+        c.cc.temporaries += 1;
+        c.parse_precedence(Precedence::Term);
+        c.cc.temporaries -= 1;
+        let next = c.current.as_ref().unwrap().ttype;
+        if !is_comparison(next) {
+            emit_comparison(c, ttype);
+            break;
+        }
+        c.emit_byte(OpCode::Tuck.into());
+        emit_comparison(c, ttype);
+        fail_jumps.push(c.emit_jump(OpCode::JumpIfFalse));
+        c.emit_byte(OpCode::Pop.into());
+        c.advance();
+        ttype = next;
+    }
+    if fail_jumps.is_empty() {
+        return;
+    }
+    let end_jump = c.emit_jump(OpCode::Jump);
+    for jump in fail_jumps {
+        c.patch_jump(jump);
+    }
+    // discard the tucked operand from underneath the failed result
+    c.emit_swap();
+    c.emit_byte(OpCode::Pop.into());
+    c.patch_jump(end_jump);
+}
+
 fn call(c: &mut Compiler, _can_assign: bool) {
     let arg_count = c.argument_list();
     c.emit_bytes(OpCode::Call.into(), arg_count as u8);
+    c.record_call();
 }
 
+// Either end of the range can be left out, in which case we push nil instead
+fn slice(c: &mut Compiler, _can_assign: bool) {
+    c.cc.temporaries += 1;
+    if c.check(TokenType::DotDot) {
+        c.emit_byte(OpCode::Nil.into());
+    } else {
+        c.expression();
+    }
+    c.consume(TokenType::DotDot, "Expect '..' in slice.");
+    c.cc.temporaries += 1;
+    if c.check(TokenType::RightBracket) {
+        c.emit_byte(OpCode::Nil.into());
+    } else {
+        c.expression();
+    }
+    c.cc.temporaries -= 2;
+    c.consume(TokenType::RightBracket, "Expect ']' after slice.");
+    c.emit_byte(OpCode::Slice.into());
+}
+
+// The scanner only makes number tokens that parse once the separators are
+// taken out
 fn number(c: &mut Compiler, _can_assign: bool) {
-    let n: f64 = c
-        .previous
-        .as_ref()
-        .unwrap()
-        .content
-        .unwrap()
-        .parse()
-        .unwrap();
+    let content = c.previous.as_ref().unwrap().content.unwrap();
+    let n: f64 = if content.contains('_') {
+        content.replace('_', "").parse().unwrap()
+    } else {
+        content.parse().unwrap()
+    };
     c.emit_constant(n.into());
 }
 
 fn string(c: &mut Compiler, _can_assign: bool) {
-    let vm = &mut c.vm;
-    let prev = &c.previous;
-    let content = prev.as_ref().unwrap().content.unwrap();
-    let w = create_string(vm, &content[1..content.len() - 1]);
-    c.emit_constant(w.into());
+    let content = c.previous.as_ref().unwrap().content.unwrap();
+    string_part(c, &content[1..content.len() - 1]);
 }
 
-fn variable(c: &mut Compiler, can_assign: bool) {
-    // unlike the book, this doesn't yet forward to named_variable() because
-    // doing so introduces a double-borrow problem we don't want to solve yet
-    let name_str = c.previous.as_ref().unwrap().content.unwrap();
-    let name_val = c.previous_identifier();
-    match c.cc.resolve_local(name_str) {
+fn string_part(c: &mut Compiler, raw: &str) {
+    let decoded = match unescape(raw) {
+        Ok(decoded) => decoded,
         Err(ce) => {
-            c.short_error(ce);
+            c.error_without_panic(ce);
+            return;
+        }
+    };
+    let w = create_string(c.vm, &decoded);
+    c.emit_constant(w.into());
+}
+
+// "a${x}b" compiles to "a" + x + "b", with x turned into a string first the
+// way print would show it
+fn interpolation(c: &mut Compiler, _can_assign: bool) {
+    let content = c.previous.as_ref().unwrap().content.unwrap();
+    string_part(c, &content[1..content.len() - 2]);
+    loop {
+        if closes_interpolation(c.current.as_ref().unwrap()) {
+            c.error_at_current("Empty interpolation.", CompileError::ParseError);
             return;
         }
-        Ok(slot) => {
-            let (get_op, set_op, arg) = match slot {
-                Some(a) => (OpCode::GetLocal, OpCode::SetLocal, Ok(a)),
-                None => match c.cc.resolve_upvalue(name_str) {
-                    Ok(Some(a)) => (OpCode::GetUpvalue, OpCode::SetUpvalue, Ok(a)),
-                    Ok(None) => (
-                        OpCode::GetGlobal,
-                        OpCode::SetGlobal,
-                        c.identifier_constant(name_val),
-                    ),
-                    Err(ce) => {
-                        c.short_error(ce);
-                        return;
-                    }
-                },
-            };
-            match arg {
-                Err(e) => c.short_error(e),
-                Ok(a) => {
-                    if can_assign && c.match_token(TokenType::Equal) {
-                        c.expression();
-                        c.emit_bytes(set_op.into(), a)
-                    } else {
-                        c.emit_bytes(get_op.into(), a)
-                    }
+        c.cc.temporaries += 1;
+        c.expression();
+        c.cc.temporaries -= 1;
+        c.emit_byte(OpCode::Stringify.into());
+        c.emit_byte(OpCode::Add.into());
+        let (content, done) = match &c.current {
+            Some(t) if closes_interpolation(t) => {
+                (t.content.unwrap(), t.ttype == TokenType::StringLiteral)
+            }
+            _ => {
+                c.error_at_current(
+                    "Expect '}' after interpolated expression.",
+                    CompileError::ParseError,
+                );
+                return;
+            }
+        };
+        c.advance();
+        let raw = if done {
+            &content[1..content.len() - 1]
+        } else {
+            &content[1..content.len() - 2]
+        };
+        if !raw.is_empty() {
+            string_part(c, raw);
+            c.emit_byte(OpCode::Add.into());
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+// The scanner only starts a string part with } when it closes a ${
+fn closes_interpolation(token: &Token) -> bool {
+    matches!(
+        token.ttype,
+        TokenType::StringLiteral | TokenType::Interpolation
+    ) && token.content.unwrap().starts_with('}')
+}
+
+fn unescape(raw: &str) -> Result<String, CompileError> {
+    let mut result = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+        // the scanner never ends a string with a lone backslash
+        match chars.next().unwrap() {
+            'n' => result.push('\n'),
+            't' => result.push('\t'),
+            'r' => result.push('\r'),
+            '0' => result.push('\0'),
+            '\\' => result.push('\\'),
+            '"' => result.push('"'),
+            '$' => result.push('$'),
+            other => return Err(CompileError::UnknownEscape(other)),
+        }
+    }
+    Ok(result)
+}
+
+pub fn compound_assignment_op(ttype: TokenType) -> Option<OpCode> {
+    match ttype {
+        TokenType::PlusEqual => Some(OpCode::Add),
+        TokenType::MinusEqual => Some(OpCode::Subtract),
+        TokenType::StarEqual => Some(OpCode::Multiply),
+        TokenType::SlashEqual => Some(OpCode::Divide),
+        _ => None,
+    }
+}
+
+fn variable(c: &mut Compiler, can_assign: bool) {
+    let name = c.previous.as_ref().unwrap().content.unwrap();
+    match c.resolve_variable(name) {
+        Err(ce) => c.short_error(ce),
+        Ok((get_op, set_op, arg)) => {
+            if let Some(op) = c.match_increment() {
+                if c.is_const(name) {
+                    c.short_error(CompileError::AssignToConst);
                 }
+                // x++ stores x + 1 but leaves the old value of x behind
+                c.emit_variable_op(get_op, arg);
+                c.emit_byte(OpCode::Dup.into());
+                c.emit_constant(Value::Number(1.0));
+                c.emit_byte(op.into());
+                c.emit_variable_op(set_op, arg);
+                c.emit_byte(OpCode::Pop.into());
+            } else if !can_assign {
+                c.emit_variable_op(get_op, arg);
+            } else if c.match_token(TokenType::Equal) {
+                if c.is_const(name) {
+                    c.short_error(CompileError::AssignToConst);
+                }
+                c.assignment_expression();
+                c.emit_variable_op(set_op, arg);
+            } else if let Some(op) = c.match_compound_assignment() {
+                if c.is_const(name) {
+                    c.short_error(CompileError::AssignToConst);
+                }
+                // x += y is x = x + y, with the same operand for the get and set
+                c.emit_variable_op(get_op, arg);
+                c.cc.temporaries += 1;
+                c.assignment_expression();
+                c.cc.temporaries -= 1;
+                c.emit_byte(op.into());
+                c.emit_variable_op(set_op, arg);
+            } else {
+                c.emit_variable_op(get_op, arg);
             }
         }
-    };
+    }
 }
 
 fn literal(c: &mut Compiler, _can_assign: bool) {
@@ -249,18 +501,79 @@ fn literal(c: &mut Compiler, _can_assign: bool) {
     }
 }
 
+// Like the book, `and` and `or` give one of their operands rather than a bool:
+// `and` gives the left one if it's falsey and the right one otherwise, and
+// `or` gives the left one if it's truthy and the right one otherwise
 fn and_op(c: &mut Compiler, _can_assign: bool) {
+    let left_start = c.operand_start;
     let end_jump = c.emit_jump(OpCode::JumpIfFalse);
     c.emit_byte(OpCode::Pop.into());
+    let right_start = c.code_len();
     c.parse_precedence(Precedence::And);
     c.patch_jump(end_jump);
+    c.record_logical(
+        OpCode::PopJumpIfFalse,
+        left_start,
+        end_jump - 1,
+        right_start,
+    );
 }
 
 fn or_op(c: &mut Compiler, _can_assign: bool) {
+    let left_start = c.operand_start;
     let else_jump = c.emit_jump(OpCode::JumpIfFalse);
     let end_jump = c.emit_jump(OpCode::Jump);
     c.patch_jump(else_jump);
     c.emit_byte(OpCode::Pop.into());
+    let right_start = c.code_len();
     c.parse_precedence(Precedence::Or);
     c.patch_jump(end_jump);
+    c.record_logical(
+        OpCode::PopJumpIfTrue,
+        left_start,
+        else_jump - 1,
+        right_start,
+    );
+}
+
+// Unlike `or`, this only skips the left operand when it's nil, so false ?? x
+// is false
+fn coalesce(c: &mut Compiler, _can_assign: bool) {
+    let end_jump = c.emit_jump(OpCode::JumpIfNotNil);
+    c.emit_byte(OpCode::Pop.into());
+    c.parse_precedence(Precedence::Coalesce);
+    c.patch_jump(end_jump);
+}
+
+// The left operand is evaluated only for its side effects. In brackets a
+// comma makes a tuple instead (see grouping), so this is only ever the comma
+// operator where there are none, as in the increment clause of a for loop or
+// an expression statement like i = i + 1, j = j - 1;
+fn comma(c: &mut Compiler, _can_assign: bool) {
+    c.emit_byte(OpCode::Pop.into());
+    c.parse_precedence(Precedence::Assignment);
+}
+
+fn block_expression(c: &mut Compiler, _can_assign: bool) {
+    c.block_expression();
+}
+
+fn lambda(c: &mut Compiler, _can_assign: bool) {
+    c.function(FunctionType::Lambda);
+}
+
+fn conditional(c: &mut Compiler, _can_assign: bool) {
+    let then_jump = c.emit_jump(OpCode::JumpIfFalse);
+    c.emit_byte(OpCode::Pop.into());
+    c.expression();
+    c.consume(
+        TokenType::Colon,
+        "Expect ':' after then branch of conditional.",
+    );
+    let else_jump = c.emit_jump(OpCode::Jump);
+    c.patch_jump(then_jump);
+    c.emit_byte(OpCode::Pop.into());
+    // parsing the else branch at the same precedence makes ?: right-associative
+    c.parse_precedence(Precedence::Conditional);
+    c.patch_jump(else_jump);
 }
@@ -2,19 +2,33 @@ use crate::LineNo;
 use std::iter::Peekable;
 use std::str::CharIndices;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
+    DotDot,
     Minus,
+    MinusEqual,
+    MinusMinus,
     Plus,
+    PlusEqual,
+    PlusPlus,
     Semicolon,
+    Question,
+    QuestionQuestion,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    Percent,
     Bang,
     BangEqual,
     Equal,
@@ -25,9 +39,15 @@ pub enum TokenType {
     GreaterEqual,
     NumberLiteral,
     StringLiteral,
+    Interpolation,
     Identifier,
     And,
+    Break,
+    Case,
     Class,
+    Const,
+    Continue,
+    Default,
     Else,
     False,
     For,
@@ -38,6 +58,7 @@ pub enum TokenType {
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
@@ -45,6 +66,7 @@ pub enum TokenType {
     EOF,
     UnexpectedCharacterError,
     UnterminatedStringError,
+    MalformedExponentError,
 }
 
 impl TokenType {
@@ -52,6 +74,7 @@ impl TokenType {
         match t {
             Self::UnexpectedCharacterError => Some("Unexpected character."),
             Self::UnterminatedStringError => Some("Unterminated string."),
+            Self::MalformedExponentError => Some("Expect digits in exponent."),
             _ => None,
         }
     }
@@ -62,28 +85,31 @@ pub struct Token<'a> {
     pub ttype: TokenType,
     pub content: Option<&'a str>,
     pub line: LineNo,
+    // counted in characters from 1, where the token starts
+    pub column: usize,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(ttype: TokenType, content: Option<&'a str>, line: LineNo) -> Self {
+    pub fn new(ttype: TokenType, content: Option<&'a str>, line: LineNo, column: usize) -> Self {
         Self {
             ttype,
             content,
             line,
+            column,
         }
     }
 }
 
 fn is_digit(c: Option<char>) -> bool {
     if let Some(c) = c {
-        return c >= '0' && c <= '9';
+        return c.is_ascii_digit();
     }
     false
 }
 
 fn is_ident(c: Option<char>) -> bool {
     if let Some(c) = c {
-        return (c >= 'A' && c <= 'Z') || (c >= 'a' && c <= 'z') || c == '_';
+        return c.is_ascii_alphabetic() || c == '_';
     }
     false
 }
@@ -96,11 +122,37 @@ fn check_keyword(word: &str, kw: &str, pos: usize, tt: TokenType) -> TokenType {
     }
 }
 
+// Scans the whole of source for tools that want the tokens without compiling
+// anything, ending with the EOF token. Errors come back as tokens with an
+// error type, as they do for the compiler.
+pub fn tokenize(source: &str) -> impl Iterator<Item = Token<'_>> {
+    let mut scanner = Scanner::new(source);
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let token = scanner.scan_token();
+        done = token.ttype == TokenType::EOF;
+        Some(token)
+    })
+}
+
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     token_start: usize,
     chars: Peekable<CharIndices<'a>>,
     line: LineNo,
+    // the byte offset and column of the last token, to count columns from
+    last_token_start: (usize, usize),
+    // one entry for each ${ we are inside, counting the braces opened since
+    interpolations: Vec<usize>,
+    // the type of the last token and where it ended, see at_increment
+    last_ttype: TokenType,
+    last_token_end: usize,
+    // whether ++ and -- can be single tokens, which they never are in clox
+    increments: bool,
 }
 
 impl<'a> Scanner<'a> {
@@ -111,14 +163,26 @@ impl<'a> Scanner<'a> {
             token_start: chars.peek().map(|(index, _c)| *index).unwrap_or(0),
             chars,
             line: 1,
+            last_token_start: (0, 1),
+            interpolations: Vec::new(),
+            last_ttype: TokenType::EOF,
+            last_token_end: 0,
+            increments: true,
         }
     }
 
+    // Scans ++ and -- as two tokens each, as clox does
+    pub fn without_increments(mut self) -> Self {
+        self.increments = false;
+        self
+    }
+
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
     fn advance(&mut self) -> Option<char> {
-        match self.chars.next() {
-            None => None,
-            Some((_index, c)) => Some(c),
-        }
+        self.chars.next().map(|(_index, c)| c)
     }
 
     fn maybe_match(&mut self, expected: char) -> bool {
@@ -171,16 +235,15 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
                 Some((_, '/')) => {
-                    if self.maybe_match_str("//") {
-                        while let Some((_, c)) = self.chars.peek() {
-                            if *c == '\n' {
-                                break;
-                            }
-                            self.advance();
-                        }
-                    } else {
+                    if !self.maybe_match_str("//") {
                         return;
                     }
+                    while let Some((_, c)) = self.chars.peek() {
+                        if *c == '\n' {
+                            break;
+                        }
+                        self.advance();
+                    }
                 }
                 _ => return,
             };
@@ -193,9 +256,26 @@ impl<'a> Scanner<'a> {
     }
 
     fn make_token(&mut self, ttype: TokenType) -> Token<'a> {
-        Token::new(ttype, Some(self.content()), self.line)
+        let column = self.column();
+        Token::new(ttype, Some(self.content()), self.line, column)
     }
 
+    // Worked out from where the last token started rather than the start of
+    // the line, so that very long lines don't take quadratic time
+    fn column(&mut self) -> usize {
+        let (offset, column) = self.last_token_start;
+        let between = &self.source[offset..self.token_start];
+        let column = match between.rfind('\n') {
+            Some(i) => between[i + 1..].chars().count() + 1,
+            None => column + between.chars().count(),
+        };
+        self.last_token_start = (self.token_start, column);
+        column
+    }
+
+    // A string containing ${ is split into an Interpolation token for each part
+    // of it that ends with ${, and a StringLiteral for the part after the last
+    // expression. The parts after the first start with the closing }.
     fn string_literal(&mut self) -> Token<'a> {
         loop {
             match self.chars.peek() {
@@ -203,6 +283,22 @@ impl<'a> Scanner<'a> {
                     self.advance();
                     return self.make_token(TokenType::StringLiteral);
                 }
+                Some((_, '$')) => {
+                    self.advance();
+                    if self.maybe_match('{') {
+                        self.interpolations.push(0);
+                        return self.make_token(TokenType::Interpolation);
+                    }
+                }
+                // skip whatever is escaped so that \" doesn't end the string;
+                // the parser decodes the escape sequences
+                Some((_, '\\')) => {
+                    self.advance();
+                    if let Some((_, '\n')) = self.chars.peek() {
+                        self.line += 1;
+                    }
+                    self.advance();
+                }
                 Some((_, c)) => {
                     if *c == '\n' {
                         self.line += 1;
@@ -214,11 +310,15 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    // An underscore can separate digits, as in 1_000, but can't come at the end
     fn consume_integers(&mut self) {
-        while match self.chars.peek() {
-            Some((_, c)) => is_digit(Some(*c)),
-            None => false,
-        } {
+        loop {
+            let mut ch = self.chars.clone();
+            match ch.next() {
+                Some((_, c)) if is_digit(Some(c)) => (),
+                Some((_, '_')) if is_digit(ch.next().map(|(_, c)| c)) => (),
+                _ => return,
+            }
             self.advance();
         }
     }
@@ -234,6 +334,16 @@ impl<'a> Scanner<'a> {
                 }
             }
         }
+        if let Some((_, 'e' | 'E')) = self.chars.peek() {
+            self.advance();
+            if let Some((_, '+' | '-')) = self.chars.peek() {
+                self.advance();
+            }
+            if !is_digit(self.chars.peek().map(|(_, c)| *c)) {
+                return self.make_token(TokenType::MalformedExponentError);
+            }
+            self.consume_integers();
+        }
         self.make_token(TokenType::NumberLiteral)
     }
 
@@ -250,12 +360,27 @@ impl<'a> Scanner<'a> {
 
     fn identifier_type(&mut self) -> TokenType {
         let word = self.content();
-        if word.len() < 1 {
+        if word.is_empty() {
             return TokenType::Identifier;
         }
         match &word[..1] {
             "a" => check_keyword(word, "and", 1, TokenType::And),
-            "c" => check_keyword(word, "class", 1, TokenType::Class),
+            "b" => check_keyword(word, "break", 1, TokenType::Break),
+            "c" => {
+                if word.len() < 2 {
+                    return TokenType::Identifier;
+                }
+                match &word[1..2] {
+                    "a" => check_keyword(word, "case", 2, TokenType::Case),
+                    "l" => check_keyword(word, "class", 2, TokenType::Class),
+                    "o" => match word.get(3..4) {
+                        Some("s") => check_keyword(word, "const", 2, TokenType::Const),
+                        _ => check_keyword(word, "continue", 2, TokenType::Continue),
+                    },
+                    _ => TokenType::Identifier,
+                }
+            }
+            "d" => check_keyword(word, "default", 1, TokenType::Default),
             "e" => check_keyword(word, "else", 1, TokenType::Else),
             "f" => {
                 if word.len() < 2 {
@@ -273,7 +398,16 @@ impl<'a> Scanner<'a> {
             "o" => check_keyword(word, "or", 1, TokenType::Or),
             "p" => check_keyword(word, "print", 1, TokenType::Print),
             "r" => check_keyword(word, "return", 1, TokenType::Return),
-            "s" => check_keyword(word, "super", 1, TokenType::Super),
+            "s" => {
+                if word.len() < 2 {
+                    return TokenType::Identifier;
+                }
+                match &word[1..2] {
+                    "u" => check_keyword(word, "super", 2, TokenType::Super),
+                    "w" => check_keyword(word, "switch", 2, TokenType::Switch),
+                    _ => TokenType::Identifier,
+                }
+            }
             "t" => {
                 if word.len() < 2 {
                     return TokenType::Identifier;
@@ -291,6 +425,22 @@ impl<'a> Scanner<'a> {
     }
 
     pub fn scan_token(&mut self) -> Token<'a> {
+        self.last_token_end = self.current();
+        let token = self.next_token();
+        self.last_ttype = token.ttype;
+        token
+    }
+
+    // ++ and -- are only single tokens straight after a name, as in x++, since
+    // that's the only place they can go. Anywhere else they're two operators,
+    // so that 1--1 is still 1 - -1 and --x is still -(-x).
+    fn at_increment(&self) -> bool {
+        self.increments
+            && self.last_ttype == TokenType::Identifier
+            && self.last_token_end == self.token_start
+    }
+
+    fn next_token(&mut self) -> Token<'a> {
         self.skip_whitespace();
         self.token_start = self.current();
         let c = self.advance();
@@ -301,19 +451,82 @@ impl<'a> Scanner<'a> {
             return self.number_literal();
         }
         match c {
-            None => Token::new(TokenType::EOF, None, self.line),
+            None => {
+                let column = self.column();
+                Token::new(TokenType::EOF, None, self.line, column)
+            }
             Some(c) => match c {
                 '(' => self.make_token(TokenType::LeftParen),
                 ')' => self.make_token(TokenType::RightParen),
-                '{' => self.make_token(TokenType::LeftBrace),
-                '}' => self.make_token(TokenType::RightBrace),
+                '{' => {
+                    if let Some(depth) = self.interpolations.last_mut() {
+                        *depth += 1;
+                    }
+                    self.make_token(TokenType::LeftBrace)
+                }
+                '}' => match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+                        self.string_literal()
+                    }
+                    Some(depth) => {
+                        *depth -= 1;
+                        self.make_token(TokenType::RightBrace)
+                    }
+                    None => self.make_token(TokenType::RightBrace),
+                },
                 ',' => self.make_token(TokenType::Comma),
-                '.' => self.make_token(TokenType::Dot),
-                '-' => self.make_token(TokenType::Minus),
-                '+' => self.make_token(TokenType::Plus),
+                ':' => self.make_token(TokenType::Colon),
+                '[' => self.make_token(TokenType::LeftBracket),
+                ']' => self.make_token(TokenType::RightBracket),
+                '.' => {
+                    if self.maybe_match('.') {
+                        self.make_token(TokenType::DotDot)
+                    } else {
+                        self.make_token(TokenType::Dot)
+                    }
+                }
+                '-' => {
+                    if self.maybe_match('=') {
+                        self.make_token(TokenType::MinusEqual)
+                    } else if self.at_increment() && self.maybe_match('-') {
+                        self.make_token(TokenType::MinusMinus)
+                    } else {
+                        self.make_token(TokenType::Minus)
+                    }
+                }
+                '+' => {
+                    if self.maybe_match('=') {
+                        self.make_token(TokenType::PlusEqual)
+                    } else if self.at_increment() && self.maybe_match('+') {
+                        self.make_token(TokenType::PlusPlus)
+                    } else {
+                        self.make_token(TokenType::Plus)
+                    }
+                }
                 ';' => self.make_token(TokenType::Semicolon),
-                '/' => self.make_token(TokenType::Slash),
-                '*' => self.make_token(TokenType::Star),
+                '?' => {
+                    if self.maybe_match('?') {
+                        self.make_token(TokenType::QuestionQuestion)
+                    } else {
+                        self.make_token(TokenType::Question)
+                    }
+                }
+                '/' => {
+                    if self.maybe_match('=') {
+                        self.make_token(TokenType::SlashEqual)
+                    } else {
+                        self.make_token(TokenType::Slash)
+                    }
+                }
+                '*' => {
+                    if self.maybe_match('=') {
+                        self.make_token(TokenType::StarEqual)
+                    } else {
+                        self.make_token(TokenType::Star)
+                    }
+                }
+                '%' => self.make_token(TokenType::Percent),
                 '!' => {
                     if self.maybe_match('=') {
                         self.make_token(TokenType::BangEqual)
@@ -348,3 +561,41 @@ impl<'a> Scanner<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_ends_with_eof() {
+        let tokens: Vec<_> = tokenize("var x = 1;\n  x")
+            .map(|t| (t.ttype, t.content, t.line, t.column))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Var, Some("var"), 1, 1),
+                (TokenType::Identifier, Some("x"), 1, 5),
+                (TokenType::Equal, Some("="), 1, 7),
+                (TokenType::NumberLiteral, Some("1"), 1, 9),
+                (TokenType::Semicolon, Some(";"), 1, 10),
+                (TokenType::Identifier, Some("x"), 2, 3),
+                (TokenType::EOF, None, 2, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_returns_errors_as_tokens() {
+        let types: Vec<_> = tokenize("1 @ \"open").map(|t| t.ttype).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::NumberLiteral,
+                TokenType::UnexpectedCharacterError,
+                TokenType::UnterminatedStringError,
+                TokenType::EOF,
+            ]
+        );
+    }
+}
@@ -1,5 +1,5 @@
-use crate::gc::Trace;
-use crate::{Chunk, RuntimeError, VMError, VM};
+use crate::gc::{Managed, Trace};
+use crate::{Chunk, RuntimeError, VMError, ValueResult, VM};
 use std::cell::RefCell;
 use std::convert::TryFrom;
 use std::fmt;
@@ -18,6 +18,7 @@ pub enum Value {
     FunctionProto(ObjectRef<Function>),
     Function(ObjectRef<Closure>),
     Native(ObjectRef<Native>),
+    Tuple(ObjectRef<Tuple>),
 }
 
 impl Value {
@@ -29,6 +30,18 @@ impl Value {
             _ => false,
         }
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::FunctionProto(_) | Value::Function(_) => "function",
+            Value::Native(_) => "native",
+            Value::Tuple(_) => "tuple",
+        }
+    }
 }
 
 impl From<bool> for Value {
@@ -43,6 +56,12 @@ impl From<f64> for Value {
     }
 }
 
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Number(n.into())
+    }
+}
+
 impl From<ObjectRef<String>> for Value {
     fn from(w: Weak<HeapEntry<String>>) -> Self {
         Value::String(w)
@@ -77,6 +96,40 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+// Only numbers with no fractional part that fit are converted, rather than
+// being truncated or saturated like `as` would do
+impl TryFrom<Value> for i64 {
+    type Error = VMError;
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v {
+            Value::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 => {
+                Ok(n as i64)
+            }
+            _ => Err(VMError::RuntimeError(RuntimeError::TypeError(
+                "integer",
+                v.to_string(),
+                true,
+            ))),
+        }
+    }
+}
+
+impl TryFrom<Value> for usize {
+    type Error = VMError;
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v {
+            Value::Number(n) if n.fract() == 0.0 && n >= 0.0 && n < usize::MAX as f64 => {
+                Ok(n as usize)
+            }
+            _ => Err(VMError::RuntimeError(RuntimeError::TypeError(
+                "non-negative integer",
+                v.to_string(),
+                true,
+            ))),
+        }
+    }
+}
+
 impl TryFrom<Value> for String {
     type Error = VMError;
     fn try_from(v: Value) -> Result<Self, Self::Error> {
@@ -97,13 +150,7 @@ impl fmt::Display for Value {
         match self {
             Self::Bool(b) => write!(f, "{}", b),
             Self::Nil => write!(f, "nil"),
-            Self::Number(n) => {
-                if *n == 0.0 && n.is_sign_negative() {
-                    write!(f, "-0")
-                } else {
-                    write!(f, "{}", n)
-                }
-            }
+            Self::Number(n) => write!(f, "{}", format_number(*n)),
             Self::String(obj) => write!(f, "{}", format_string(obj)),
             Self::FunctionProto(obj) => write!(f, "{}", format_function(obj)),
             Self::Function(obj) => write!(
@@ -111,7 +158,8 @@ impl fmt::Display for Value {
                 "{}",
                 format_function(&obj.upgrade().unwrap().content.function)
             ),
-            Self::Native(_) => write!(f, "<native fn>"),
+            Self::Native(obj) => write!(f, "{}", obj.upgrade().unwrap().content),
+            Self::Tuple(obj) => write!(f, "{}", obj.upgrade().unwrap().content),
         }
     }
 }
@@ -119,11 +167,23 @@ impl fmt::Display for Value {
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Value::Bool(a), Value::Bool(b)) => (a == b),
+            (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
-            (Value::Number(a), Value::Number(b)) => (a == b),
-            // Value equality is pointer equality for interned strings
+            (Value::Number(a), Value::Number(b)) => a == b,
+            // Value equality is pointer equality for interned strings, and
+            // identity for functions, as in clox
             (Value::String(a), Value::String(b)) => Weak::ptr_eq(a, b),
+            (Value::FunctionProto(a), Value::FunctionProto(b)) => Weak::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Weak::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Weak::ptr_eq(a, b),
+            // Tuples can't be changed once they're made, so two with equal
+            // items are equal (and one can't contain itself)
+            (Value::Tuple(a), Value::Tuple(b)) => {
+                Weak::ptr_eq(a, b) || {
+                    let (a, b) = (a.upgrade().unwrap(), b.upgrade().unwrap());
+                    a.content.items == b.content.items
+                }
+            }
             _ => false,
         }
     }
@@ -143,21 +203,27 @@ where
     }
 }
 
-pub fn manage<T: 'static>(vm: &mut VM, value: T) -> ObjectRef<T>
+pub fn manage<T: Managed>(vm: &mut VM, value: T) -> ObjectRef<T>
 where
     ObjectRoot<T>: Trace,
 {
+    // collect before allocating so that the new object can't be freed at once
+    #[cfg(feature = "stress_gc")]
+    vm.stress_collect();
     let entry = HeapEntry::<T> {
         content: value,
         marked: RefCell::new(false),
     };
     let oroot = Rc::new(entry);
     let oref = Rc::downgrade(&oroot);
-    vm.objects.push(Box::new(oroot));
+    vm.shade_new(&oroot);
+    T::arena(&mut vm.objects).push(oroot);
     oref
 }
 
 pub fn create_string(vm: &mut VM, s: &str) -> ObjectRef<String> {
+    #[cfg(feature = "stress_gc")]
+    vm.stress_collect();
     match vm.strings.get(s) {
         Some(InternedString(oroot)) => Rc::downgrade(oroot),
         None => {
@@ -169,12 +235,51 @@ pub fn create_string(vm: &mut VM, s: &str) -> ObjectRef<String> {
             let oref = Rc::downgrade(&oroot);
             let interned = InternedString(Rc::clone(&oroot));
             vm.strings.insert(interned);
-            vm.objects.push(Box::new(oroot));
+            vm.shade_new(&oroot);
+            String::arena(&mut vm.objects).push(oroot);
             oref
         }
     }
 }
 
+// Formats a number the way clox's printf("%g") does: six significant digits,
+// no trailing zeros, and an exponent for very large or small magnitudes. The
+// one difference is that negative zero prints as 0.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return "nan".to_owned();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_owned();
+    }
+    if n == 0.0 {
+        return "0".to_owned();
+    }
+    // rounding to six digits can carry into the exponent, so find it this way
+    // rather than with log10
+    let sci = format!("{:.5e}", n);
+    let (mantissa, exponent) = sci.split_at(sci.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if !(-4..6).contains(&exponent) {
+        format!(
+            "{}e{}{:02}",
+            strip_zeros(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        strip_zeros(&format!("{:.*}", (5 - exponent) as usize, n)).to_owned()
+    }
+}
+
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
 pub fn format_string(w: &ObjectRef<String>) -> String {
     let c = &w.upgrade().unwrap().content;
     format!("\"{}\"", c).to_owned()
@@ -184,21 +289,40 @@ pub fn format_function(w: &ObjectRef<Function>) -> String {
     format_function_name(&w.upgrade().unwrap().content)
 }
 
+// Functions show their arity too, as in <fn add/2>, since a function's name
+// alone doesn't say how to call it
 pub fn format_function_name(f: &Function) -> String {
     match &f.name {
+        None if f.anonymous => format!("<fn anonymous/{}>", f.arity),
         None => "<script>".to_owned(),
-        Some(obj) => format!("<fn {}>", obj.upgrade().unwrap().content).to_owned(),
+        Some(obj) => format!("<fn {}/{}>", obj.upgrade().unwrap().content, f.arity),
     }
 }
 
 pub fn printable_value(v: Value) -> String {
     if let Value::String(oref) = &v {
         let s = &oref.upgrade().unwrap().content;
-        return format!("{}", s).to_owned();
+        return s.clone();
     }
     format!("{}", v)
 }
 
+// What clox's print shows, which leaves out the arity of functions and the
+// names of natives
+pub fn clox_printable_value(v: Value) -> String {
+    let function = match &v {
+        Value::FunctionProto(oref) => oref.upgrade().unwrap(),
+        Value::Function(oref) => oref.upgrade().unwrap().content.function.upgrade().unwrap(),
+        Value::Native(_) => return "<native fn>".to_owned(),
+        _ => return printable_value(v),
+    };
+    match &function.content.name {
+        None if function.content.anonymous => "<fn anonymous>".to_owned(),
+        None => "<script>".to_owned(),
+        Some(obj) => format!("<fn {}>", obj.upgrade().unwrap().content),
+    }
+}
+
 pub struct InternedString(pub ObjectRoot<String>);
 
 impl Hash for InternedString {
@@ -209,7 +333,7 @@ impl Hash for InternedString {
 
 impl PartialEq for InternedString {
     fn eq(&self, other: &Self) -> bool {
-        &self.0.content == &other.0.content
+        self.0.content == other.0.content
     }
 }
 
@@ -241,14 +365,22 @@ impl fmt::Display for InternedString {
     }
 }
 
+#[derive(Clone, Copy)]
 pub enum FunctionType {
     Function,
+    Lambda,
     Script,
 }
 
 pub struct Function {
+    // the script and anonymous functions have no name
     pub name: Option<ObjectRef<String>>,
+    pub anonymous: bool,
     pub arity: usize,
+    // Where to start running when some of the parameters with defaults are
+    // left out: the offset of the code for each default, and then the start
+    // of the body. This is empty if no parameter has a default.
+    pub defaults: Vec<usize>,
     pub chunk: Chunk,
     pub upvalue_count: usize,
 }
@@ -258,11 +390,25 @@ impl Function {
         let name = name.map(|s| create_string(vm, s));
         Self {
             name,
+            anonymous: false,
             arity,
+            defaults: Vec::new(),
             chunk: Chunk::new(),
             upvalue_count: 0,
         }
     }
+
+    // These let the compiled code be checked without running it
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn instruction_count(&self) -> usize {
+        crate::dis::count_instructions(&self.chunk)
+    }
+
+    pub fn disassemble(&self) -> String {
+        let mut s = String::new();
+        crate::dis::write_chunk(&mut s, &self.chunk, &format_function_name(self)).unwrap();
+        s
+    }
 }
 
 impl fmt::Display for Function {
@@ -315,20 +461,53 @@ impl fmt::Display for Upvalue {
     }
 }
 
-pub type NativeFn = fn(arg_count: usize, args: &[Value]) -> Value;
+pub type NativeFn = fn(vm: &mut VM, arg_count: usize, args: &[Value]) -> ValueResult;
 
 pub struct Native {
+    pub name: String,
     pub function: NativeFn,
 }
 
 impl Native {
-    pub fn new(function: NativeFn) -> Self {
-        Self { function }
+    pub fn new(name: &str, function: NativeFn) -> Self {
+        Self {
+            name: name.to_owned(),
+            function,
+        }
     }
 }
 
 impl fmt::Display for Native {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<native fn>")
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+// What return (a, b) gives back, for var (x, y) = ... to take apart again.
+// The items can't be changed, so unlike an upvalue it needs no write barrier.
+pub struct Tuple {
+    pub items: Vec<Value>,
+}
+
+impl Tuple {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+}
+
+// A tuple of one item keeps its comma, as in (1,), to look like what made it
+impl fmt::Display for Tuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(")?;
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        if self.items.len() == 1 {
+            write!(f, ",")?;
+        }
+        write!(f, ")")
     }
 }
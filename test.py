@@ -84,7 +84,7 @@ def test_file(filename):
                 runtime_error_line = n + 1
                 expected_exit_code = 70
     result = subprocess.run(
-        [binary, filename], capture_output=True, text=True, encoding="utf-8")
+        [binary, "--compat-clox", filename], capture_output=True, text=True, encoding="utf-8")
     ok = True
     if expected_runtime_error is not None:
         error_lines = result.stderr.split("\n")
@@ -0,0 +1,869 @@
+mod common;
+
+use common::{eval, lox_file, repl, rlox, rlox_with_input};
+
+#[test]
+fn instruction_limit_stops_an_infinite_loop() {
+    let run = eval(&["--max-instructions", "1000"], "while (true) {}");
+    assert_eq!(run.code, 70);
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Instruction limit of 1000 exceeded.\n[line 1] in script\n"
+    );
+}
+
+#[test]
+fn instruction_limit_counts_every_instruction() {
+    // CONSTANT, PRINT, NIL, RETURN
+    let run = eval(&["--max-instructions", "4"], "print 1;");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "1\n"));
+    let run = eval(&["--max-instructions", "3"], "print 1;");
+    assert_eq!((run.code, run.stdout.as_str()), (70, "1\n"));
+}
+
+#[test]
+fn function_cannot_replace_a_constant() {
+    let run = eval(&[], "const x = 1;\nfun x() {}");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 2:5] Error at 'x': Can't assign to a constant."));
+    // a local function can still shadow it
+    let run = eval(
+        &[],
+        "const x = 1;\nfun f() { fun x() {} return x; }\nprint f();",
+    );
+    assert_eq!(run.code, 0);
+    assert_eq!(run.stdout, "<fn x/0>\n");
+}
+
+#[test]
+fn constant_from_a_line_that_failed_to_compile_is_forgotten() {
+    let run = repl("const x = 1 +;\nvar x = 2;\nprint x;\nconst y = 3;\ny = 4;\n");
+    assert_eq!(run.stdout, "> > > 2\n> > > \n");
+    assert!(run.stderr.contains("Expect expression."));
+    assert!(run.stderr.contains("Can't assign to a constant."));
+}
+
+#[test]
+fn increments_need_a_variable() {
+    let run = eval(&[], "print 3++;");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 1:9] Error at '+': Expect expression."));
+    let run = eval(&[], "const c = 1;\nc++;");
+    assert_eq!(run.code, 65);
+    assert!(run.stderr.contains("Can't assign to a constant."));
+}
+
+#[test]
+fn clox_has_no_increments() {
+    let run = eval(&["--compat-clox"], "var a = 5;\nprint a--1;\nprint a;");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "6\n5\n"));
+    let run = eval(&["--compat-clox"], "var a = 5;\na++;");
+    assert_eq!(run.code, 65);
+    assert_eq!(run.stderr, "[line 2] Error at '+': Expect expression.\n");
+}
+
+#[test]
+fn unpacking_needs_the_right_number_of_items() {
+    let run = eval(&[], "fun f() {\n  return (1, 2, 3);\n}\nvar (a, b) = f();");
+    assert_eq!(run.code, 70);
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Expected 2 values to unpack but got 3.\n[line 4] in script\n"
+    );
+    let run = eval(&[], "var (a, b) = 1;");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Expected a tuple value but found: 1."));
+}
+
+#[test]
+fn print_shows_a_tuple() {
+    let run = eval(&[], "print (1, 2);\nprint 1, 2;");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "(1, 2)\n2\n"));
+}
+
+#[test]
+fn empty_interpolation_is_an_error() {
+    let run = eval(&[], "print \"a${}b\";");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 1:11] Error at '}b\"': Empty interpolation."));
+}
+
+#[test]
+fn exit_ends_the_process_with_its_code() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("exit.lox");
+    std::fs::write(&path, "print 1;\nexit(3);\nprint 2;\n").unwrap();
+    let run = rlox(&[path.to_str().unwrap()]);
+    assert_eq!((run.code, run.stdout.as_str()), (3, "1\n"));
+    let run = eval(&[], "exit(256);");
+    assert_eq!(run.code, 70);
+}
+
+// What clox from the book prints for the same programs
+#[test]
+fn compat_clox_matches_clox() {
+    let program = "\
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+print fib(10);
+print 1 / 3;
+print 123456789;
+print \"con\" + \"cat\";
+print fib;
+print clock;
+fun makeCounter() {
+  var i = 0;
+  fun count() {
+    i = i + 1;
+    return i;
+  }
+  return count;
+}
+var counter = makeCounter();
+counter();
+print counter();
+print !nil;
+print \"a\" < \"b\";
+";
+    let run = eval(&["--compat-clox"], program);
+    assert_eq!(
+        run.stdout,
+        "55\n0.333333\n1.23457e+08\nconcat\n<fn fib>\n<native fn>\n2\ntrue\n"
+    );
+    assert_eq!(
+        run.stderr,
+        "Operands must be numbers.\n[line 23] in script\n"
+    );
+    assert_eq!(run.code, 70);
+
+    let run = eval(
+        &["--compat-clox"],
+        "fun f(a) {\n  return \"s\" + a;\n}\nf(1);",
+    );
+    assert_eq!(
+        run.stderr,
+        "Operands must be two numbers or two strings.\n[line 2] in f()\n[line 4] in script\n"
+    );
+
+    // clox allows 64 frames, counting the script's
+    let run = eval(
+        &["--compat-clox"],
+        "fun recurse(n) {\n  return recurse(n + 1);\n}\nrecurse(0);",
+    );
+    let trace: Vec<&str> = run.stderr.lines().collect();
+    assert_eq!(trace.len(), 65);
+    assert_eq!(trace[0], "Stack overflow.");
+    assert!(trace[1..64]
+        .iter()
+        .all(|&line| line == "[line 2] in recurse()"));
+    assert_eq!(trace[64], "[line 4] in script");
+}
+
+#[test]
+fn fresh_loop_variables_are_captured_per_iteration() {
+    let code = "var f;\nfor (var i = 0; i < 3; i = i + 1) {\n  fun get() { return i; }\n  if (i == 0) f = get;\n}\nprint f();";
+    assert_eq!(eval(&[], code).stdout, "3\n");
+    assert_eq!(eval(&["--fresh-loop-variables"], code).stdout, "0\n");
+}
+
+#[test]
+fn return_at_top_level_is_a_compile_error() {
+    let run = eval(&[], "print 1;\nif (true) { return; }");
+    assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+    assert!(run
+        .stderr
+        .starts_with("[line 2:13] Error at 'return': Can't return from top-level code."));
+    let run = eval(&["--compat-clox"], "return 1;");
+    assert_eq!(
+        run.stderr,
+        "[line 1] Error at 'return': Can't return from top-level code.\n"
+    );
+    // a function declared at the top level can still return
+    let run = eval(&[], "fun f() { return 1; }\nprint f();");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "1\n"));
+}
+
+// Each call is the last instruction on its line, so looking up the line
+// after it would give the line of the ; instead
+#[test]
+fn errors_in_calls_are_reported_on_the_call_line() {
+    let run = eval(&[], "fun f() {\n  return nil + 1;\n}\nf()\n;");
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Invalid types for + operator: nil, 1.\n[line 2] in f()\n[line 4] in script\n"
+    );
+    let run = eval(&[], "var g = nil;\ng()\n;");
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Can only call functions and classes.\n[line 2] in script\n"
+    );
+    let run = eval(&[], "fun f(a) {}\nf(1,\n2)\n;");
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Expected 1 arguments but got 2.\n[line 3] in script\n"
+    );
+}
+
+#[test]
+fn malformed_number_literals_are_reported_on_their_line() {
+    for (literal, column) in [("1e", 7), ("1e+", 7), ("2.5E-", 7)] {
+        let run = eval(&[], &format!("print 1;\n\nprint {};", literal));
+        assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+        assert!(run.stderr.starts_with(&format!(
+            "[line 3:{}] Error: Expect digits in exponent.",
+            column
+        )));
+    }
+    // a separator has to be followed by a digit, so these stop the number
+    let run = eval(&[], "print 1_;");
+    assert!(run
+        .stderr
+        .starts_with("[line 1:8] Error at '_': Expect ';' after value."));
+    let run = eval(&[], "print 1._5;");
+    assert!(run
+        .stderr
+        .starts_with("[line 1:8] Error at '.': Expect ';' after value."));
+}
+
+#[test]
+fn chained_comparison_is_a_compile_error() {
+    let run = eval(&[], "print 1;\nprint 1 < 2 < 3;");
+    assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+    assert!(run.stderr.starts_with(
+        "[line 2:13] Error at '<': Can't chain comparisons; use 'and' to combine them."
+    ));
+    // brackets show it was meant, and equality isn't a comparison
+    let run = eval(&[], "print (1 < 2) == true;\nprint 1 == 2 == false;");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "true\ntrue\n"));
+    // clox compares the bool with the number at runtime
+    let run = eval(&["--compat-clox"], "print 1 < 2 < 3;");
+    assert_eq!(run.code, 70);
+    assert_eq!(
+        run.stderr,
+        "Operands must be numbers.\n[line 1] in script\n"
+    );
+}
+
+#[test]
+fn chained_comparisons_evaluate_each_operand_once() {
+    let run = eval(
+        &["--chained-comparisons"],
+        "var n = 0;\n\
+         fun mid() { n = n + 1; return 2; }\n\
+         print 1 < mid() <= 3;\n\
+         print 3 < mid() < 5;\n\
+         print n;\n\
+         print 1 < 2 < 3 < 4 >= 4;",
+    );
+    assert_eq!(run.stdout, "true\nfalse\n2\ntrue\n");
+}
+
+// Damaging any one byte of a compiled file has to give an error, or at
+// worst a different program, but never a panic
+#[test]
+fn damaged_bytecode_never_panics() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let source = dir.join("damaged.lox");
+    let compiled = dir.join("damaged.loxc");
+    std::fs::write(
+        &source,
+        "fun f(a, b) {\n  var c = a;\n  fun g() { return c + b; }\n  return g;\n}\nprint f(1, 2)();\n",
+    )
+    .unwrap();
+    let run = rlox(&[
+        "--compile",
+        source.to_str().unwrap(),
+        "-o",
+        compiled.to_str().unwrap(),
+    ]);
+    assert_eq!(run.code, 0);
+    let bytes = std::fs::read(&compiled).unwrap();
+    let damaged = dir.join("damaged_copy.loxc");
+    for offset in 0..bytes.len() {
+        let mut copy = bytes.clone();
+        copy[offset] ^= 0xff;
+        std::fs::write(&damaged, &copy).unwrap();
+        let run = rlox(&["--run", damaged.to_str().unwrap()]);
+        assert!(
+            [0, 65, 70].contains(&run.code),
+            "byte {} gave exit code {}: {}",
+            offset,
+            run.code,
+            run.stderr
+        );
+    }
+}
+
+// The helpers set NO_COLOR and stderr isn't a terminal, so only --color
+// should turn color on
+#[test]
+fn color_is_only_used_when_forced_on() {
+    let run = eval(&["--color"], "print nil + 1;");
+    assert_eq!(
+        run.stderr,
+        "\x1b[31merror:\x1b[0m Invalid types for + operator: nil, 1.\n  \x1b[36m[line 1]\x1b[0m in script\n"
+    );
+    let run = eval(&["--pretty-errors"], "print 1 +;");
+    assert!(run
+        .stderr
+        .starts_with("\x1b[31merror:\x1b[0m Expect expression.\n\x1b[36m[line 1:10]\x1b[0m"));
+    assert!(run.stderr.contains("\x1b[33m^\x1b[0m"));
+    for options in [&["--no-color"][..], &[], &["--color", "--no-color"]] {
+        for code in ["print nil + 1;", "print 1 +;"] {
+            let run = eval(options, code);
+            assert!(!run.stderr.is_empty());
+            assert!(
+                !run.stderr.contains('\x1b'),
+                "{:?}: {}",
+                options,
+                run.stderr
+            );
+        }
+    }
+}
+
+#[test]
+fn gc_root_trace_lists_globals_and_the_stack() {
+    // enough garbage to make the collector run while f is still going
+    let code = "var keep = \"kept\";\n\
+                fun f(local) {\n\
+                  for (var i = 0; i < 5000; i = i + 1) str(i) + \"x\";\n\
+                }\n\
+                f(\"arg\");";
+    let run = eval(&["--trace-gc-roots"], code);
+    assert_eq!(run.code, 0);
+    assert!(run.stdout.contains("--gc roots\n"));
+    assert!(run.stdout.contains("\nglobal \"keep\": \"kept\"\n"));
+    assert!(run.stdout.contains("\nstack slot 2: \"arg\"\n"));
+    assert!(run
+        .stdout
+        .contains("\nframe 1 closure: <closure <fn f/1>>\n"));
+    assert_eq!(eval(&[], code).stdout, "");
+}
+
+#[test]
+fn incremental_gc_keeps_live_objects() {
+    let path = lox_file("live_objects.lox");
+    let run = rlox(&["--test", "--incremental-gc", "--trace-gc-roots", &path]);
+    assert_eq!(run.code, 0, "{}", run.stderr);
+    // collections really did happen along the way
+    assert!(run.stdout.matches("--gc roots\n").count() > 2);
+    assert!(run.stdout.ends_with("\n2 passed, 0 failed\n"));
+}
+
+#[test]
+fn dump_globals_lists_them_sorted_with_their_types() {
+    let run = eval(
+        &["--dump-globals"],
+        "var zebra = \"z\";\nvar apple = 1;\nfun middle() {}",
+    );
+    assert_eq!(run.code, 0);
+    // the natives are globals too
+    let globals: Vec<&str> = run
+        .stdout
+        .lines()
+        .filter(|line| !line.contains(": native = "))
+        .collect();
+    assert_eq!(
+        globals,
+        [
+            "== globals ==",
+            "apple: number = 1",
+            "middle: function = <fn middle/0>",
+            "zebra: string = z"
+        ]
+    );
+}
+
+#[test]
+fn modulo_by_zero_is_only_an_error_with_strict_division() {
+    let run = eval(&[], "print 7 % 3;\nprint 7 % 0;");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "1\nnan\n"));
+    let run = eval(&["--strict-division"], "print 7 % 3;\nprint 7 % 0;");
+    assert_eq!((run.code, run.stdout.as_str()), (70, "1\n"));
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Division by zero.\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn break_and_continue_need_an_enclosing_loop() {
+    let run = eval(&[], "break;");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 1:1] Error at 'break': Can't use 'break' outside of a loop."));
+    let run = eval(&[], "fun f() { continue; }");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .contains("Can't use 'continue' outside of a loop."));
+    // A loop around the function declaration doesn't count.
+    let run = eval(&[], "while (true) { fun f() { break; } }");
+    assert_eq!(run.code, 65);
+    assert!(run.stderr.contains("Can't use 'break' outside of a loop."));
+}
+
+#[test]
+fn dump_after_gc_counts_the_survivors_by_type() {
+    // the script and make are closures too, and each kept closure has
+    // closed over its own x
+    let code = "fun make() { var x = 1; fun get() { return x; } return get; }\n\
+                var a = make();\n\
+                var b = make();\n\
+                var t = (1, 2);\n\
+                for (var i = 0; i < 5000; i = i + 1) str(i) + \"x\";";
+    let run = eval(&["--dump-after-gc"], code);
+    assert_eq!(run.code, 0);
+    let last = run.stdout.lines().last().unwrap();
+    assert!(last.starts_with("--gc survivors: closure=4 function=3 native=23 string="));
+    assert!(last.ends_with(" tuple=1 upvalue=2"));
+    assert_eq!(eval(&[], code).stdout, "");
+}
+
+#[test]
+fn slices_out_of_range_are_runtime_errors() {
+    // "héllo" is five characters but six bytes
+    let run = eval(&[], "print \"héllo\"[2..6];");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Slice index 6 is out of range for a string of length 5."));
+    let run = eval(&[], "print \"héllo\"[3..1];");
+    assert_eq!(run.code, 70);
+    assert!(run.stderr.contains("Slice start 3 is after slice end 1."));
+}
+
+#[test]
+fn multiple_assignment_needs_a_value_per_target() {
+    let run = eval(&[], "var a;\nvar b;\na, b = 1, 2, 3;");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 3:14] Error at '3': Expected 2 values to assign but got 3."));
+    let run = eval(&[], "var a;\nvar b;\na, b = 1;");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .contains("Expected 2 values to assign but got 1."));
+}
+
+#[test]
+fn errors_in_included_files_report_their_own_lines() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("include");
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = |name: &str, code: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, code).unwrap();
+        path.to_str().unwrap().to_owned()
+    };
+    let lib = file(
+        "lib.lox",
+        "var shared = \"from lib\";\n\nfun bad() {\n  return nil + 1;\n}\n",
+    );
+    let main = file(
+        "main.lox",
+        "var x = 1;\n@include \"lib.lox\"\nprint shared;\nbad();\n",
+    );
+    let run = rlox(&[&main]);
+    assert_eq!((run.code, run.stdout.as_str()), (70, "from lib\n"));
+    assert!(run.stderr.ends_with(&format!(
+        "\n[{} line 4] in bad()\n[line 4] in script\n",
+        lib
+    )));
+
+    let broken = file("broken.lox", "print 1;\nprint 2 +;\n");
+    let main = file("uses_broken.lox", "var y = 1;\n@include \"broken.lox\"\n");
+    let run = rlox(&[&main]);
+    assert_eq!(run.code, 65);
+    assert!(run.stderr.starts_with(&format!(
+        "[{} line 2:10] Error at ';': Expect expression.",
+        broken
+    )));
+
+    let a = file("a.lox", "@include \"b.lox\"\n");
+    let b = file("b.lox", "print 1;\n@include \"a.lox\"\n");
+    let run = rlox(&[&a]);
+    assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+    assert_eq!(
+        run.stderr,
+        format!("Include cycle: {} -> {} -> {}\n", a, b, a)
+    );
+}
+
+#[test]
+fn only_strings_can_be_sliced() {
+    for (code, kind) in [
+        ("print 5[0..1];", "number"),
+        ("print nil[..];", "nil"),
+        ("print true[1..];", "bool"),
+    ]
+    .iter()
+    {
+        let run = eval(&[], code);
+        assert_eq!(run.code, 70);
+        assert_eq!(
+            run.stderr,
+            format!(
+                "Runtime error: Can't index a value of type {}.\n[line 1] in script\n",
+                kind
+            )
+        );
+    }
+}
+
+#[test]
+fn output_limit_stops_a_print_loop() {
+    // each "x\n" is two bytes, so the sixth print would go over
+    let run = eval(&["--max-output-bytes", "11"], "while (true) print \"x\";");
+    assert_eq!((run.code, run.stdout.as_str()), (70, "x\nx\nx\nx\nx\n"));
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Output limit of 11 bytes exceeded.\n[line 1] in script\n"
+    );
+}
+
+#[test]
+fn strings_only_compare_with_strings() {
+    let run = eval(&[], "print \"a\" < 1;");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Can't compare a string with a non-string: \"a\", 1."));
+    let run = eval(&[], "print 1 >= \"a\";");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Can't compare a string with a non-string: 1, \"a\"."));
+}
+
+#[test]
+fn escapes_in_strings_are_decoded() {
+    let run = eval(&[], "print \"tab\\there\";");
+    assert_eq!((run.code, run.stdout.as_str()), (0, "tab\there\n"));
+    let run = eval(&[], "print \"a\\nb \\\"q\\\" \\\\ \\0\\r.\";");
+    assert_eq!(run.stdout, "a\nb \"q\" \\ \0\r.\n");
+    let run = eval(&[], "print 1;\nprint \"a\\qb\";");
+    assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+    assert!(run
+        .stderr
+        .starts_with("[line 2:7] Error at '\"a\\qb\"': Unknown escape sequence '\\q'."));
+}
+
+#[test]
+fn numbers_print_like_clox() {
+    let cases = [
+        ("1", "1"),
+        ("3.14", "3.14"),
+        ("-0", "0"),
+        ("0.1 + 0.2", "0.3"),
+        ("1 / 3", "0.333333"),
+        ("100000", "100000"),
+        ("1000000", "1e+06"),
+        ("999999.5", "1e+06"),
+        ("123456789", "1.23457e+08"),
+        ("0.0001", "0.0001"),
+        ("0.00001234", "1.234e-05"),
+        ("1e300", "1e+300"),
+        ("-1e-300", "-1e-300"),
+    ];
+    let code: String = cases
+        .iter()
+        .map(|(n, _)| format!("print {};\n", n))
+        .collect();
+    let expected: String = cases.iter().map(|(_, s)| format!("{}\n", s)).collect();
+    assert_eq!(eval(&[], &code).stdout, expected);
+    // --dump-globals formats them the same way
+    let run = eval(&["--dump-globals"], "var big = 1e300;\nvar zero = -0;");
+    assert!(run.stdout.contains("big: number = 1e+300\n"));
+    assert!(run.stdout.contains("zero: number = 0\n"));
+}
+
+#[test]
+fn math_natives_check_their_arguments() {
+    let cases = [
+        ("sqrt();", "Expected 1 arguments but got 0."),
+        ("floor(1, 2);", "Expected 1 arguments but got 2."),
+        ("pow(2);", "Expected 2 arguments but got 1."),
+        ("sqrt(\"x\");", "Expected a number value but found: \"x\"."),
+        ("floor(nil);", "Expected a number value but found: nil."),
+        ("ceil(true);", "Expected a number value but found: true."),
+        ("abs(\"1\");", "Expected a number value but found: \"1\"."),
+        (
+            "pow(2, \"x\");",
+            "Expected a number value but found: \"x\".",
+        ),
+    ];
+    for (code, error) in cases.iter() {
+        let run = eval(&[], code);
+        assert_eq!(run.code, 70, "{}", code);
+        assert!(
+            run.stderr
+                .starts_with(&format!("Runtime error: {}\n", error)),
+            "{}: {}",
+            code,
+            run.stderr
+        );
+    }
+}
+
+#[test]
+fn len_needs_a_string() {
+    let run = eval(&[], "print len(1);");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Expected a string value but found: 1.\n"));
+    let run = eval(&[], "print len(nil);");
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Expected a string value but found: nil.\n"));
+}
+
+#[test]
+fn substring_checks_its_bounds() {
+    let cases = [
+        (
+            "substring(\"abc\", 2, 4);",
+            "Index 4 is out of bounds for a string of length 3.",
+        ),
+        (
+            "substring(\"abc\", -1, 1);",
+            "Index -1 is out of bounds for a string of length 3.",
+        ),
+        (
+            "substring(\"abc\", 0.5, 1);",
+            "Index 0.5 is out of bounds for a string of length 3.",
+        ),
+        (
+            "substring(\"héllo\", 0, 6);",
+            "Index 6 is out of bounds for a string of length 5.",
+        ),
+        (
+            "substring(\"abc\", 2, 1);",
+            "Slice start 2 is after slice end 1.",
+        ),
+        ("toUpper(1);", "Expected a string value but found: 1."),
+    ];
+    for (code, error) in cases.iter() {
+        let run = eval(&[], code);
+        assert_eq!(run.code, 70, "{}", code);
+        assert_eq!(
+            run.stderr,
+            format!("Runtime error: {}\n[line 1] in script\n", error)
+        );
+    }
+}
+
+#[test]
+fn read_line_reads_standard_input() {
+    let code = "var line = readLine();\n\
+                while (line != nil) {\n\
+                  print \"got \" + line;\n\
+                  line = readLine();\n\
+                }";
+    let run = rlox_with_input(&["-e", code], "first line\nsecond\r\n");
+    assert_eq!(
+        (run.code, run.stdout.as_str()),
+        (0, "got first line\ngot second\n")
+    );
+    // the last line doesn't need a newline
+    let run = rlox_with_input(&["-e", code], "no newline");
+    assert_eq!(run.stdout, "got no newline\n");
+    let run = rlox_with_input(&["-e", code], "");
+    assert_eq!(run.stdout, "");
+}
+
+#[test]
+fn default_parameters_come_last() {
+    let run = eval(&[], "fun f(a = 1, b) {}");
+    assert_eq!(run.code, 65);
+    assert!(run.stderr.starts_with(
+        "[line 1:14] Error at 'b': Parameters without a default can't follow ones with a default."
+    ));
+    // only the parameters with defaults can be left out
+    let run = eval(&[], "fun f(a, b = 1) {}\nf();");
+    assert_eq!(run.code, 70);
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Expected 1 arguments but got 0."));
+    let run = eval(&[], "fun f(a, b = 1) {}\nf(1, 2, 3);");
+    assert!(run
+        .stderr
+        .starts_with("Runtime error: Expected 2 arguments but got 3."));
+}
+
+#[test]
+fn constants_cannot_be_assigned() {
+    let run = eval(&[], "const x = 1;\nx = 2;");
+    assert_eq!((run.code, run.stdout.as_str()), (65, ""));
+    assert!(run
+        .stderr
+        .starts_with("[line 2:3] Error at '=': Can't assign to a constant."));
+    // locals, upvalues and compound assignment too
+    for code in [
+        "fun f() {\n  const y = 1;\n  y = 3;\n}",
+        "{\n  const k = 1;\n  fun h() { k = 2; }\n}",
+        "const a = 1;\nprint a;\na += 1;",
+    ]
+    .iter()
+    {
+        let run = eval(&[], code);
+        assert_eq!(run.code, 65, "{}", code);
+        assert!(run.stderr.starts_with("[line 3:"), "{}", run.stderr);
+        assert!(run.stderr.contains("Can't assign to a constant."));
+    }
+}
+
+#[test]
+fn errors_give_the_column_of_the_token() {
+    // columns count characters rather than bytes
+    let run = eval(&[], "var a = 1;\nvar b = 2;\nprint \"héllo\" a;");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 3:15] Error at 'a': Expect ';' after value."));
+    let run = eval(&[], "var a = 1;\n  fun f( {");
+    assert!(run
+        .stderr
+        .starts_with("[line 2:10] Error at '{': Expect parameter name."));
+}
+
+#[test]
+fn errors_show_the_line_with_a_caret() {
+    let run = eval(&[], "print 1 +;");
+    assert_eq!(
+        run.stderr,
+        "[line 1:10] Error at ';': Expect expression.\n    | print 1 +;\n    |          ^\n"
+    );
+    // tabs are kept in the padding so the caret lines up however they show
+    let run = eval(&[], "if (true) {\n\tprint 1 +;\n}");
+    assert_eq!(
+        run.stderr,
+        "[line 2:11] Error at ';': Expect expression.\n    | \tprint 1 +;\n    | \t         ^\n"
+    );
+}
+
+#[test]
+fn division_by_zero_is_only_an_error_with_strict_division() {
+    let code = "print 1 / 2;\nprint 1 / 0;\nprint -1 / 0;\nprint 0 / 0;";
+    let run = eval(&[], code);
+    assert_eq!(
+        (run.code, run.stdout.as_str()),
+        (0, "0.5\ninf\n-inf\nnan\n")
+    );
+    let run = eval(&["--strict-division"], code);
+    assert_eq!((run.code, run.stdout.as_str()), (70, "0.5\n"));
+    assert_eq!(
+        run.stderr,
+        "Runtime error: Division by zero.\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn callables_display_with_their_arity() {
+    let code = "fun add(a, b) {}\n\
+                fun outer() { fun inner(a = 1) {} return inner; }\n\
+                print add;\n\
+                print outer();\n\
+                print fun(x) {};\n\
+                print clock;";
+    let run = eval(&[], code);
+    assert_eq!(
+        run.stdout,
+        "<fn add/2>\n<fn inner/1>\n<fn anonymous/1>\n<native fn clock>\n"
+    );
+    // the trace shows them the same way
+    let run = eval(&["--trace"], "fun add(a, b) {}\nvar c = clock;");
+    assert!(run.stdout.contains("[ <script> ][ <fn add/2> ]"));
+    assert!(run.stdout.contains("[ <script> ][ <native fn clock> ]"));
+}
+
+#[test]
+fn dump_parse_shows_the_nesting() {
+    let run = eval(&["--dump-parse"], "var a = 1;\nif (a > 0) print a + 2;");
+    assert_eq!(run.code, 0);
+    assert_eq!(
+        run.stdout,
+        "var declaration (line 1)\n\
+         \x20 NumberLiteral 1 (line 1)\n\
+         if statement (line 2)\n\
+         \x20 Identifier a (line 2)\n\
+         \x20 Greater > (line 2)\n\
+         \x20   NumberLiteral 0 (line 2)\n\
+         \x20 print statement (line 2)\n\
+         \x20   Identifier a (line 2)\n\
+         \x20   Plus + (line 2)\n\
+         \x20     NumberLiteral 2 (line 2)\n\
+         3\n"
+    );
+}
+
+#[test]
+fn random_starts_from_a_fixed_seed() {
+    let run = eval(&[], "print random();\nprint random();");
+    assert_eq!(run.stdout, "0.677211\n0.745529\n");
+    assert_eq!(eval(&[], "print random();").stdout, "0.677211\n");
+}
+
+#[test]
+fn only_one_trailing_comma_is_allowed() {
+    let run = eval(&[], "fun f(a,,) {}");
+    assert_eq!(run.code, 65);
+    assert!(run
+        .stderr
+        .starts_with("[line 1:9] Error at ',': Expect parameter name."));
+    let run = eval(&[], "print clock(,);");
+    assert!(run
+        .stderr
+        .starts_with("[line 1:13] Error at ',': Expect expression."));
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_interrupts_the_repl() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    writeln!(stdin, "print \"looping\"; while (true) {{}}").unwrap();
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("looping"));
+    let killed = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    writeln!(stdin, "print \"after\";").unwrap();
+    drop(stdin);
+    let mut rest = String::new();
+    stdout.read_to_string(&mut rest).unwrap();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert!(child.wait().unwrap().success());
+    assert!(rest.contains("after"));
+    assert!(stderr.starts_with("Runtime error: Interrupted.\n"));
+}
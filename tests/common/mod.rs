@@ -0,0 +1,69 @@
+// Helpers for running the rlox binary from the integration tests
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+pub struct Run {
+    pub stdout: String,
+    pub stderr: String,
+    pub code: i32,
+}
+
+impl From<Output> for Run {
+    fn from(output: Output) -> Self {
+        Run {
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            code: output.status.code().expect("rlox was killed by a signal"),
+        }
+    }
+}
+
+pub fn rlox(args: &[&str]) -> Run {
+    Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .env("NO_COLOR", "1")
+        .output()
+        .expect("couldn't run rlox")
+        .into()
+}
+
+// Runs a snippet given with -e, after any other options
+pub fn eval(options: &[&str], code: &str) -> Run {
+    let mut args = options.to_vec();
+    args.extend(&["-e", code]);
+    rlox(&args)
+}
+
+// Types the input into the REPL, one line at a time
+pub fn repl(input: &str) -> Run {
+    rlox_with_input(&[], input)
+}
+
+// Runs rlox with the input piped to its standard input
+pub fn rlox_with_input(args: &[&str], input: &str) -> Run {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .env("NO_COLOR", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("couldn't run rlox");
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(input.as_bytes()).unwrap();
+    drop(stdin);
+    child.wait_with_output().unwrap().into()
+}
+
+pub fn lox_file(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("lox")
+        .join(name)
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
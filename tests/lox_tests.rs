@@ -0,0 +1,70 @@
+mod common;
+
+use common::{eval, lox_file, rlox};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn lox_files() -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(lox_file(""))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("lox".as_ref()))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty());
+    paths
+}
+
+#[test]
+fn lox_files_pass() {
+    let mut failures = Vec::new();
+    for path in lox_files() {
+        let run = rlox(&["--test", path.to_str().unwrap()]);
+        if run.code != 0 || !run.stdout.ends_with(" 0 failed\n") {
+            failures.push(format!("{}:\n{}{}", path.display(), run.stdout, run.stderr));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+// Every script runs the same from a file written by --compile as it does
+// from source
+#[test]
+fn compiled_lox_files_run_the_same() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    for path in lox_files() {
+        let compiled = dir.join(path.with_extension("loxc").file_name().unwrap());
+        let compiled = compiled.to_str().unwrap();
+        let run = rlox(&["--compile", path.to_str().unwrap(), "-o", compiled]);
+        assert_eq!(run.code, 0, "{}: {}", path.display(), run.stderr);
+        let from_source = rlox(&["--test", path.to_str().unwrap()]);
+        let from_bytecode = rlox(&["--test", "--run", compiled]);
+        assert_eq!(
+            from_bytecode.stdout,
+            from_source.stdout,
+            "{}",
+            path.display()
+        );
+        assert_eq!(from_bytecode.code, from_source.code, "{}", path.display());
+    }
+}
+
+#[test]
+fn failed_expectations_are_reported() {
+    let run = eval(
+        &["--test"],
+        "expect(1, 1);\nexpect(1 + 1, 3);\nexpect(2, 2);",
+    );
+    assert_eq!(
+        run.stdout,
+        "[line 2] expect failed: expected 3 but got 2\n2 passed, 1 failed\n"
+    );
+    assert_eq!(run.code, 1);
+}
+
+#[test]
+fn expect_is_only_defined_for_tests() {
+    let run = eval(&[], "expect(1, 1);");
+    assert_eq!(run.code, 70);
+    assert!(run.stderr.contains("Undefined variable 'expect'"));
+}